@@ -1,6 +1,11 @@
+#[cfg(feature = "print-code")]
 use super::common::OpCode;
+use super::error::Span;
+#[cfg(feature = "print-code")]
+use super::obj::ObjFunction;
 use super::value::*;
 use std::ops::Shr;
+use std::rc::Rc;
 
 #[derive(Copy, Clone)]
 struct Line {
@@ -12,6 +17,10 @@ pub struct Chunk {
     pub code: Vec<u8>,
     constants: Vec<Value>,
     lines: Vec<Line>,
+    // Source span of each byte in `code`, for runtime diagnostics.
+    spans: Vec<Span>,
+    // The source the chunk was compiled from, which spans index into.
+    pub source: Rc<str>,
 }
 
 impl Chunk {
@@ -20,15 +29,21 @@ impl Chunk {
             code: vec![],
             constants: vec![],
             lines: vec![],
+            spans: vec![],
+            source: Rc::from(""),
         }
     }
 
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
     #[inline(always)]
     pub fn get_constant(&self, ind: u8) -> Value {
         self.constants[ind as usize]
     }
 
-    fn set_line(&mut self, line: i32) -> () {
+    fn set_line(&mut self, line: i32) {
         match self.lines.last_mut() {
             Some(last) => {
                 if last.line_num == line {
@@ -50,36 +65,43 @@ impl Chunk {
     pub fn get_line(&self, offset: usize) -> i32 {
         let mut curr = 0;
         for x in self.lines.iter() {
-            if curr < offset {
-                curr += x.count as usize;
-            } else {
+            curr += x.count as usize;
+            if offset < curr {
                 return x.line_num;
             }
         }
-        return curr as i32;
+        self.lines.last().map_or(0, |l| l.line_num)
     }
 
-    pub fn write(&mut self, byte: u8, line: i32) -> () {
+    pub fn get_span(&self, offset: usize) -> Span {
+        self.spans.get(offset).copied().unwrap_or_default()
+    }
+
+    pub fn write(&mut self, byte: u8, line: i32, span: Span) {
         self.code.push(byte);
         self.set_line(line);
+        self.spans.push(span);
     }
 
-    pub fn write_long(&mut self, num: u16, line: i32) -> () {
+    pub fn write_long(&mut self, num: u16, line: i32, span: Span) {
         let lower = num & 0x00ff;
         let higher = num & 0xff00;
-        self.code.push(lower as u8);
-        self.code.push(higher.shr(8) as u8);
-        self.set_line(line);
+        self.write(lower as u8, line, span);
+        self.write(higher.shr(8) as u8, line, span);
+    }
+
+    #[inline(always)]
+    pub fn read_long(&self, offset: usize) -> u16 {
+        self.code[offset] as u16 | (self.code[offset + 1] as u16) << 8
     }
 
-    pub fn free(&mut self) -> () {
-        self.code = vec![];
-        self.constants = vec![];
-        self.lines = vec![];
+    pub fn patch_long(&mut self, offset: usize, num: u16) {
+        self.code[offset] = (num & 0x00ff) as u8;
+        self.code[offset + 1] = (num & 0xff00).shr(8) as u8;
     }
 
-    #[cfg(debug_assertions)]
-    pub fn disassemble(&self, name: &str) -> () {
+    #[cfg(feature = "print-code")]
+    pub fn disassemble(&self, name: &str) {
         println!("== {} ==", name);
         let mut offset = 0;
         while offset < self.code.len() {
@@ -87,10 +109,10 @@ impl Chunk {
         }
     }
 
-    #[cfg(not(debug_assertions))]
-    pub fn disassemble(&self, name: &str) -> () {}
+    #[cfg(not(feature = "print-code"))]
+    pub fn disassemble(&self, _name: &str) {}
 
-    #[cfg(debug_assertions)]
+    #[cfg(feature = "print-code")]
     pub fn disassemble_instruction(&self, offset: usize) -> usize {
         print!("{:04} ", offset);
         let line = self.get_line(offset);
@@ -122,12 +144,39 @@ impl Chunk {
             OpCode::OpGreaterEqual => simple_instruction("OP_GREATER_EQUAL".into(), offset),
             OpCode::OpLessEqual => simple_instruction("OP_LESS_EQUAL".into(), offset),
             OpCode::OpNotEqual => simple_instruction("OP_NOT_EQUAL".into(), offset),
+            OpCode::OpPrint => simple_instruction("OP_PRINT".into(), offset),
+            OpCode::OpPop => simple_instruction("OP_POP".into(), offset),
+            OpCode::OpDefineGlobal => self.constant_instruction("OP_DEFINE_GLOBAL".into(), offset),
+            OpCode::OpGetGlobal => self.constant_instruction("OP_GET_GLOBAL".into(), offset),
+            OpCode::OpSetGlobal => self.constant_instruction("OP_SET_GLOBAL".into(), offset),
+            OpCode::OpGetLocal => self.byte_instruction("OP_GET_LOCAL".into(), offset),
+            OpCode::OpSetLocal => self.byte_instruction("OP_SET_LOCAL".into(), offset),
+            OpCode::OpJump => self.jump_instruction("OP_JUMP".into(), 1, offset),
+            OpCode::OpJumpIfFalse => self.jump_instruction("OP_JUMP_IF_FALSE".into(), 1, offset),
+            OpCode::OpLoop => self.jump_instruction("OP_LOOP".into(), -1, offset),
+            OpCode::OpCall => self.byte_instruction("OP_CALL".into(), offset),
+            OpCode::OpClosure => self.closure_instruction("OP_CLOSURE".into(), offset),
+            OpCode::OpGetUpvalue => self.byte_instruction("OP_GET_UPVALUE".into(), offset),
+            OpCode::OpSetUpvalue => self.byte_instruction("OP_SET_UPVALUE".into(), offset),
+            OpCode::OpCloseUpvalue => simple_instruction("OP_CLOSE_UPVALUE".into(), offset),
+            OpCode::OpClass => self.constant_instruction("OP_CLASS".into(), offset),
+            OpCode::OpGetProperty => self.constant_instruction("OP_GET_PROPERTY".into(), offset),
+            OpCode::OpSetProperty => self.constant_instruction("OP_SET_PROPERTY".into(), offset),
+            OpCode::OpMethod => self.constant_instruction("OP_METHOD".into(), offset),
+            OpCode::OpInvoke => self.invoke_instruction("OP_INVOKE".into(), offset),
+            OpCode::OpDup => simple_instruction("OP_DUP".into(), offset),
+            OpCode::OpInherit => simple_instruction("OP_INHERIT".into(), offset),
+            OpCode::OpGetSuper => self.constant_instruction("OP_GET_SUPER".into(), offset),
+            OpCode::OpSuperInvoke => self.invoke_instruction("OP_SUPER_INVOKE".into(), offset),
+            OpCode::OpBuildList => self.byte_instruction("OP_BUILD_LIST".into(), offset),
+            OpCode::OpIndexGet => simple_instruction("OP_INDEX_GET".into(), offset),
+            OpCode::OpIndexSet => simple_instruction("OP_INDEX_SET".into(), offset),
+            OpCode::OpDup2 => simple_instruction("OP_DUP2".into(), offset),
+            OpCode::OpBuildMap => self.byte_instruction("OP_BUILD_MAP".into(), offset),
         }
     }
 
-    #[cfg(not(debug_assertions))]
-    pub fn disassemble_instruction(&self, offset: usize) -> () {}
-
+    #[cfg(feature = "print-code")]
     fn constant_instruction(&self, name: String, offset: usize) -> usize {
         let constant = self.code[offset + 1] as usize;
         print!("{:16} {:4} ", name, constant);
@@ -136,12 +185,69 @@ impl Chunk {
         offset + 2
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
+    #[cfg(feature = "print-code")]
+    fn byte_instruction(&self, name: String, offset: usize) -> usize {
+        let slot = self.code[offset + 1];
+        println!("{:16} {:4}", name, slot);
+        offset + 2
+    }
+
+    #[cfg(feature = "print-code")]
+    fn jump_instruction(&self, name: String, sign: i64, offset: usize) -> usize {
+        let jump = self.read_long(offset + 1) as i64;
+        println!(
+            "{:16} {:4} -> {}",
+            name,
+            offset,
+            offset as i64 + 3 + sign * jump
+        );
+        offset + 3
+    }
+
+    #[cfg(feature = "print-code")]
+    fn invoke_instruction(&self, name: String, offset: usize) -> usize {
+        let constant = self.code[offset + 1] as usize;
+        let arg_count = self.code[offset + 2];
+        print!("{:16} ({} args) {:4} ", name, arg_count, constant);
+        print_value(self.constants[constant]);
+        println!();
+        offset + 3
+    }
+
+    #[cfg(feature = "print-code")]
+    fn closure_instruction(&self, name: String, offset: usize) -> usize {
+        let constant = self.code[offset + 1] as usize;
+        print!("{:16} {:4} ", name, constant);
+        print_value(self.constants[constant]);
+        println!();
+        let upvalue_count = self.constants[constant]
+            .downcast::<ObjFunction>()
+            .map_or(0, |function| function.upvalue_count);
+        let mut offset = offset + 2;
+        for _ in 0..upvalue_count {
+            let kind = if self.code[offset] == 1 {
+                "local"
+            } else {
+                "upvalue"
+            };
+            println!(
+                "{:04}    |                     {} {}",
+                offset,
+                kind,
+                self.code[offset + 1]
+            );
+            offset += 2;
+        }
+        offset
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> usize {
         self.constants.push(value);
-        (self.constants.len() - 1) as u8
+        self.constants.len() - 1
     }
 }
 
+#[cfg(feature = "print-code")]
 fn simple_instruction(name: String, offset: usize) -> usize {
     println!("{}", name);
     offset + 1
@@ -1,6 +1,7 @@
 use num_enum::IntoPrimitive;
 use num_enum::UnsafeFromPrimitive;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, IntoPrimitive, UnsafeFromPrimitive, Copy, Clone)]
 #[repr(u8)]
 pub enum OpCode {
@@ -20,11 +21,41 @@ pub enum OpCode {
     OpMultiply,
     OpDivide,
     OpNot,
-    OpNotEqual, // OpTest,
-                // OpChoose
+    OpNotEqual,
+    OpPrint,
+    OpPop,
+    OpDefineGlobal,
+    OpGetGlobal,
+    OpSetGlobal,
+    OpGetLocal,
+    OpSetLocal,
+    OpJump,
+    OpJumpIfFalse,
+    OpLoop,
+    OpCall,
+    OpClosure,
+    OpGetUpvalue,
+    OpSetUpvalue,
+    OpCloseUpvalue,
+    OpClass,
+    OpGetProperty,
+    OpSetProperty,
+    OpMethod,
+    OpInvoke,
+    OpDup,
+    OpInherit,
+    OpGetSuper,
+    OpSuperInvoke,
+    OpBuildList,
+    OpIndexGet,
+    OpIndexSet,
+    OpDup2,
+    OpBuildMap,
 }
 
-#[derive(Debug, IntoPrimitive, UnsafeFromPrimitive, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(
+    Debug, IntoPrimitive, UnsafeFromPrimitive, Copy, Clone, Ord, PartialOrd, Eq, PartialEq,
+)]
 #[repr(u8)]
 pub enum TokenType {
     LeftParen,
@@ -80,4 +111,9 @@ pub enum TokenType {
 
     Question,
     Colon,
+    LeftBracket,
+    RightBracket,
 }
+
+// Number of TokenType variants, used to size the compiler's parse rule table.
+pub const TOKEN_COUNT: usize = TokenType::RightBracket as usize + 1;
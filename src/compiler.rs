@@ -1,12 +1,22 @@
 use crate::chunk::Chunk;
+use crate::common::OpCode;
 use crate::common::OpCode::*;
-use crate::common::TokenType;
-use crate::common::TokenType::{Eof, RightParen};
+use crate::common::TokenType::{
+    Colon, Comma, Eof, Equal, Identifier, LeftBrace, LeftParen, RightBrace, RightBracket,
+    RightParen, Semicolon,
+};
+use crate::common::{TokenType, TOKEN_COUNT};
+use crate::error::CompileError;
+use crate::gc::Gc;
+use crate::obj::ObjFunction;
 use crate::parser::Precedence::*;
 use crate::parser::{Parser, Precedence};
+use crate::scanner::Token;
+use crate::vm::VM;
 use crate::{obj, value::Value};
+use std::rc::Rc;
 
-type ParseFn = Option<fn(&mut Compiler) -> ()>;
+type ParseFn = Option<fn(&mut Compiler, bool)>;
 
 pub struct ParseRule {
     pub prefix: ParseFn,
@@ -14,17 +24,96 @@ pub struct ParseRule {
     pub precedence: Precedence,
 }
 
+const LOCALS_MAX: usize = 256;
+const UPVALUES_MAX: usize = 256;
+const ARGS_MAX: usize = 255;
+const ELEMENTS_MAX: usize = 255;
+
+struct Local<'source_lifetime> {
+    name: &'source_lifetime str,
+    // -1 while the local's initializer is still being compiled.
+    depth: i32,
+    is_captured: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Upvalue {
+    index: u8,
+    // Whether the upvalue captures a local of the enclosing function or one
+    // of its upvalues.
+    is_local: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+// Per-function compilation state, one for each function being compiled
+// with the innermost function last.
+struct FunctionState<'source_lifetime> {
+    // Allocated on the heap by end_compiler once the body is compiled.
+    function: ObjFunction,
+    function_type: FunctionType,
+    locals: Vec<Local<'source_lifetime>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: i32,
+}
+
+impl<'source_lifetime> FunctionState<'source_lifetime> {
+    fn new(vm: &mut VM, source: &Rc<str>, function_type: FunctionType, name: Option<&str>) -> Self {
+        let name = name.map(|name| obj::copy_string(vm, name));
+        if let Some(name) = name {
+            vm.push_compiler_root(Value::obj(name));
+        }
+        let mut function = ObjFunction::new(name);
+        function.chunk.source = Rc::clone(source);
+        let mut locals = Vec::with_capacity(LOCALS_MAX);
+        // Slot zero holds the function being called, or the receiver for
+        // methods.
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            _ => "",
+        };
+        locals.push(Local {
+            name: slot_zero,
+            depth: 0,
+            is_captured: false,
+        });
+        FunctionState {
+            function,
+            function_type,
+            locals,
+            upvalues: vec![],
+            scope_depth: 0,
+        }
+    }
+}
+
+// Per-class compilation state, one for each class declaration enclosing the
+// code being compiled.
+struct ClassState {
+    has_superclass: bool,
+}
+
 pub struct Compiler<'source_lifetime> {
     parser: Parser<'source_lifetime>,
-    chunk: &'source_lifetime mut Chunk,
+    vm: &'source_lifetime mut VM,
+    // Shared by every chunk compiled from it, for runtime diagnostics.
+    source: Rc<str>,
+    states: Vec<FunctionState<'source_lifetime>>,
+    classes: Vec<ClassState>,
 }
 
 impl<'source_lifetime> Compiler<'source_lifetime> {
-    const PARSE_RULES: [ParseRule; 44] = [
+    const PARSE_RULES: [ParseRule; TOKEN_COUNT] = [
         ParseRule {
             prefix: Some(Compiler::grouping),
-            infix: None,
-            precedence: PrecNone,
+            infix: Some(Compiler::call),
+            precedence: PrecCall,
         }, // TOKEN_LEFT_PAREN 0
         ParseRule {
             prefix: None,
@@ -32,7 +121,7 @@ impl<'source_lifetime> Compiler<'source_lifetime> {
             precedence: PrecNone,
         }, // TOKEN_RIGHT_PAREN 1
         ParseRule {
-            prefix: None,
+            prefix: Some(Compiler::map),
             infix: None,
             precedence: PrecNone,
         }, // TOKEN_LEFT_BRACE 2
@@ -48,8 +137,8 @@ impl<'source_lifetime> Compiler<'source_lifetime> {
         }, // TOKEN_COMMA 4
         ParseRule {
             prefix: None,
-            infix: None,
-            precedence: PrecNone,
+            infix: Some(Compiler::dot),
+            precedence: PrecCall,
         }, // TOKEN_DOT 5
         ParseRule {
             prefix: Some(Compiler::unary),
@@ -137,7 +226,7 @@ impl<'source_lifetime> Compiler<'source_lifetime> {
             precedence: PrecComparison,
         }, // TOKEN_LESS_EQUAL 22
         ParseRule {
-            prefix: None,
+            prefix: Some(Compiler::variable),
             infix: None,
             precedence: PrecNone,
         }, // TOKEN_IDENTIFIER 23
@@ -153,8 +242,8 @@ impl<'source_lifetime> Compiler<'source_lifetime> {
         }, // TOKEN_NUMBER 25
         ParseRule {
             prefix: None,
-            infix: None,
-            precedence: PrecNone,
+            infix: Some(Compiler::and),
+            precedence: PrecAnd,
         }, // TOKEN_AND 26
         ParseRule {
             prefix: None,
@@ -193,8 +282,8 @@ impl<'source_lifetime> Compiler<'source_lifetime> {
         }, // TOKEN_NIL 33
         ParseRule {
             prefix: None,
-            infix: None,
-            precedence: PrecNone,
+            infix: Some(Compiler::or),
+            precedence: PrecOr,
         }, // TOKEN_OR 34
         ParseRule {
             prefix: None,
@@ -207,12 +296,12 @@ impl<'source_lifetime> Compiler<'source_lifetime> {
             precedence: PrecNone,
         }, // TOKEN_RETURN 36
         ParseRule {
-            prefix: None,
+            prefix: Some(Compiler::super_),
             infix: None,
             precedence: PrecNone,
         }, // TOKEN_SUPER 37
         ParseRule {
-            prefix: None,
+            prefix: Some(Compiler::this),
             infix: None,
             precedence: PrecNone,
         }, // TOKEN_THIS 38
@@ -235,283 +324,558 @@ impl<'source_lifetime> Compiler<'source_lifetime> {
             prefix: None,
             infix: None,
             precedence: PrecNone,
-        }, // TOKEN_ERROR 42
+        }, // TOKEN_EOF 42
         ParseRule {
             prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }, // TOKEN_EOF 43
-           // ParseRule {
-           //     prefix: None,
-           //     infix: Some(Compiler::choose),
-           //     precedence: PrecTernary
-           // }, // TOKEN_QUESTION
-           // ParseRule {
-           //     prefix: None,
-           //     infix: Some(Compiler::question),
-           //     precedence: PrecTernary
-           // }, // TOKEN_COLON
-    ];
-
-    const PARSE_RULES_DBG: [&'static str; 44] = [
-        "ParseRule {
-            prefix: Some(Compiler::grouping),
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_LEFT_PAREN
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_RIGHT_PAREN
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_LEFT_BRACE
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_RIGHT_BRACE
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_COMMA
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_DOT
-        "ParseRule {
-            prefix: Some(Compiler::unary),
-            infix: Some(Compiler::binary),
-            precedence: PrecTerm,
-        }", // TOKEN_MINUS
-        "ParseRule {
-            prefix: None,
-            infix: Some(Compiler::binary),
-            precedence: PrecTerm,
-        }", // TOKEN_PLUS
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_SEMICOLON
-        "ParseRule {
-            prefix: None,
-            infix: Some(Compiler::binary),
-            precedence: PrecFactor,
-        }", // TOKEN_SLASH
-        "ParseRule {
-            prefix: None,
-            infix: Some(Compiler::binary),
-            precedence: PrecFactor,
-        }", // TOKEN_STAR
-        "ParseRule {
-            prefix: Some(Compiler::unary),
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_BANG
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_BANG_EQUAL
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_EQUAL
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_PLUS_EQUAL
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_MINUS_EQUAL
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_STAR_EQUAL
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_SLASH_EQUAL
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_EQUAL_EQUAL
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_GREATER
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_GREATER_EQUAL
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_LESS
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_LESS_EQUAL
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_IDENTIFIER
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_STRING
-        "ParseRule {
-            prefix: Some(Compiler::number),
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_NUMBER
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_AND
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_CLASS
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_ELSE
-        "ParseRule {
-            prefix: Some(Compiler::literal),
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_FALSE
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_FOR
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_FUN
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_IF
-        "ParseRule {
-            prefix: Some(Compiler::literal),
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_NIL
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_OR
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_PRINT
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_RETURN
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_SUPER
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_THIS
-        "ParseRule {
-            prefix: Some(Compiler::literal),
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_TRUE
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_VAR
-        "ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: PrecNone,
-        }", // TOKEN_WHILE
-        "ParseRule {
+            infix: Some(Compiler::choose),
+            precedence: PrecTernary,
+        }, // TOKEN_QUESTION 43
+        ParseRule {
             prefix: None,
             infix: None,
             precedence: PrecNone,
-        }", // TOKEN_ERROR
-        "ParseRule {
+        }, // TOKEN_COLON 44
+        ParseRule {
+            prefix: Some(Compiler::list),
+            infix: Some(Compiler::index),
+            precedence: PrecCall,
+        }, // TOKEN_LEFT_BRACKET 45
+        ParseRule {
             prefix: None,
             infix: None,
             precedence: PrecNone,
-        }", // TOKEN_EOF
-            // ParseRule {
-            //     prefix: None,
-            //     infix: Some(Compiler::choose),
-            //     precedence: PrecTernary
-            // }, // TOKEN_QUESTION
-            // ParseRule {
-            //     prefix: None,
-            //     infix: Some(Compiler::question),
-            //     precedence: PrecTernary
-            // }, // TOKEN_COLON
+        }, // TOKEN_RIGHT_BRACKET 46
     ];
 
-    pub fn new(source: &'source_lifetime str, chunk: &'source_lifetime mut Chunk) -> Self {
+    pub fn new(source: &'source_lifetime str, vm: &'source_lifetime mut VM) -> Self {
+        let parser = Parser::new(source);
+        let source = Rc::from(source);
+        let script = FunctionState::new(vm, &source, FunctionType::Script, None);
         Compiler {
-            parser: Parser::new(source),
-            chunk,
+            parser,
+            vm,
+            source,
+            states: vec![script],
+            classes: vec![],
         }
     }
 
-    pub fn compile(&mut self, source: &'source_lifetime str) -> bool {
-        self.parser = Parser::new(source);
+    pub fn compile(&mut self) -> Result<Gc<ObjFunction>, Vec<CompileError>> {
         self.parser.advance();
+        while !self.parser.match_token(Eof) {
+            self.declaration();
+        }
+        let function = self.end_compiler();
+        if self.parser.had_error {
+            Err(std::mem::take(&mut self.parser.errors))
+        } else {
+            Ok(function)
+        }
+    }
+
+    #[inline(always)]
+    fn state(&self) -> &FunctionState<'source_lifetime> {
+        self.states.last().unwrap()
+    }
+
+    #[inline(always)]
+    fn state_mut(&mut self) -> &mut FunctionState<'source_lifetime> {
+        self.states.last_mut().unwrap()
+    }
+
+    #[inline(always)]
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+
+    fn declaration(&mut self) {
+        if self.parser.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.parser.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.parser.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+        if self.parser.panic_mode {
+            self.parser.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.parser.consume(Identifier, "Expected class name.");
+        let class_name = self.parser.prev.as_ref().unwrap().lexeme;
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_bytes(OpClass.into(), name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        if self.parser.match_token(TokenType::Less) {
+            self.parser.consume(Identifier, "Expected superclass name.");
+            Compiler::variable(self, false);
+            if class_name == self.parser.prev.as_ref().unwrap().lexeme {
+                self.parser.error("A class can't inherit from itself.");
+            }
+
+            // The superclass lives in a local named "super" so methods can
+            // capture it as an upvalue.
+            self.begin_scope();
+            self.add_local("super");
+            self.define_variable(0);
+
+            self.named_variable(class_name, false);
+            self.emit_byte(OpInherit.into());
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        self.named_variable(class_name, false);
+        // Without a brace there is no body to skip, so leave the rest to
+        // `synchronize`.
+        if self.parser.match_token(LeftBrace) {
+            while !self.parser.check(RightBrace) && !self.parser.check(Eof) {
+                self.method();
+                if self.parser.panic_mode {
+                    self.skip_class_body();
+                }
+            }
+            self.parser
+                .consume(RightBrace, "Expected } after class body.");
+        } else {
+            self.parser
+                .error_at_current("Expected { before class body.");
+        }
+        self.emit_byte(OpPop.into());
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    // Skips to the brace closing the class body, so the members after a
+    // broken one aren't parsed as statements or reported again.
+    fn skip_class_body(&mut self) {
+        let mut depth = 0;
+        while !self.parser.check(Eof) {
+            if self.parser.check(RightBrace) {
+                if depth == 0 {
+                    return;
+                }
+                depth -= 1;
+            } else if self.parser.check(LeftBrace) {
+                depth += 1;
+            }
+            self.parser.advance();
+        }
+    }
+
+    fn method(&mut self) {
+        if !self.parser.match_token(Identifier) {
+            self.parser.error_at_current("Expected method name.");
+            return;
+        }
+        let name = self.parser.prev.as_ref().unwrap().lexeme;
+        let constant = self.identifier_constant(name);
+        let function_type = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_bytes(OpMethod.into(), constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expected function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.parser.prev.as_ref().unwrap().lexeme;
+        let state = FunctionState::new(self.vm, &self.source, function_type, Some(name));
+        self.states.push(state);
+        self.begin_scope();
+
+        self.parser
+            .consume(LeftParen, "Expected ( after function name.");
+        if !self.parser.check(RightParen) {
+            loop {
+                let function = &mut self.state_mut().function;
+                function.arity += 1;
+                let arity = function.arity;
+                if arity > ARGS_MAX {
+                    self.parser
+                        .error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expected parameter name.");
+                self.define_variable(constant);
+                if !self.parser.match_token(Comma) {
+                    break;
+                }
+            }
+        }
+        self.parser
+            .consume(RightParen, "Expected ) after parameters.");
+        self.parser
+            .consume(LeftBrace, "Expected { before function body.");
+        self.block();
+
+        // No end_scope, the whole frame is discarded on return.
+        let upvalues = std::mem::take(&mut self.state_mut().upvalues);
+        let function = self.end_compiler();
+        let constant = self.make_constant(Value::obj(function));
+        self.emit_bytes(OpClosure.into(), constant);
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local.into(), upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expected variable name.");
+        if self.parser.match_token(Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpNil.into());
+        }
+        self.parser
+            .consume(Semicolon, "Expected ; after variable declaration.");
+        self.define_variable(global);
+    }
+
+    fn parse_variable(&mut self, msg: &'static str) -> u8 {
+        self.parser.consume(Identifier, msg);
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+        let name = self.parser.prev.as_ref().unwrap().lexeme;
+        self.identifier_constant(name)
+    }
+
+    fn declare_variable(&mut self) {
+        let scope_depth = self.state().scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+        let name = self.parser.prev.as_ref().unwrap().lexeme;
+        let duplicate = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|l| l.depth == -1 || l.depth >= scope_depth)
+            .any(|l| l.name == name);
+        if duplicate {
+            self.parser
+                .error("Already a variable with this name in this scope.");
+        }
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: &'source_lifetime str) {
+        if self.state().locals.len() == LOCALS_MAX {
+            self.parser.error("Too many local variables in function.");
+            return;
+        }
+        self.state_mut().locals.push(Local {
+            name,
+            depth: -1,
+            is_captured: false,
+        });
+    }
+
+    fn define_variable(&mut self, global: u8) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_bytes(OpDefineGlobal.into(), global);
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = state.scope_depth;
+        }
+    }
+
+    fn resolve_local(&mut self, state: usize, name: &str) -> Option<u8> {
+        let (slot, local) = self.states[state]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, l)| l.name == name)?;
+        if local.depth == -1 {
+            self.parser
+                .error("Can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
+
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Option<u8> {
+        if state == 0 {
+            return None;
+        }
+        if let Some(local) = self.resolve_local(state - 1, name) {
+            self.states[state - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(state, local, true));
+        }
+        let upvalue = self.resolve_upvalue(state - 1, name)?;
+        Some(self.add_upvalue(state, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &mut self.states[state].upvalues;
+        if let Some(existing) = upvalues.iter().position(|&u| u == upvalue) {
+            return existing as u8;
+        }
+        if upvalues.len() == UPVALUES_MAX {
+            self.parser.error("Too many closure variables in function.");
+            return 0;
+        }
+        upvalues.push(upvalue);
+        let count = upvalues.len();
+        self.states[state].function.upvalue_count = count;
+        (count - 1) as u8
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> u8 {
+        let obj = obj::copy_string(self.vm, name);
+        self.make_constant(Value::obj(obj))
+    }
+
+    fn statement(&mut self) {
+        if self.parser.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.parser.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.parser.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.parser.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.parser.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.parser.match_token(LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.parser.consume(LeftParen, "Expected ( after 'if'.");
+        self.expression();
+        self.parser
+            .consume(RightParen, "Expected ) after condition.");
+
+        let then_jump = self.emit_jump(OpJumpIfFalse.into());
+        self.emit_byte(OpPop.into());
+        self.statement();
+        let else_jump = self.emit_jump(OpJump.into());
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpPop.into());
+        if self.parser.match_token(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.parser.consume(LeftParen, "Expected ( after 'while'.");
+        self.expression();
+        self.parser
+            .consume(RightParen, "Expected ) after condition.");
+
+        let exit_jump = self.emit_jump(OpJumpIfFalse.into());
+        self.emit_byte(OpPop.into());
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpPop.into());
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.parser.consume(LeftParen, "Expected ( after 'for'.");
+        if self.parser.match_token(Semicolon) {
+            // No initializer.
+        } else if self.parser.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.parser.match_token(Semicolon) {
+            self.expression();
+            self.parser
+                .consume(Semicolon, "Expected ; after loop condition.");
+            exit_jump = Some(self.emit_jump(OpJumpIfFalse.into()));
+            self.emit_byte(OpPop.into());
+        }
+
+        if !self.parser.match_token(RightParen) {
+            let body_jump = self.emit_jump(OpJump.into());
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            self.emit_byte(OpPop.into());
+            self.parser
+                .consume(RightParen, "Expected ) after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpPop.into());
+        }
+        self.end_scope();
+    }
+
+    fn block(&mut self) {
+        while !self.parser.check(RightBrace) && !self.parser.check(Eof) {
+            self.declaration();
+        }
+        self.parser.consume(RightBrace, "Expected } after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+        let scope_depth = self.state().scope_depth;
+        while let Some(local) = self.state().locals.last() {
+            if local.depth <= scope_depth {
+                break;
+            }
+            if local.is_captured {
+                self.emit_byte(OpCloseUpvalue.into());
+            } else {
+                self.emit_byte(OpPop.into());
+            }
+            self.state_mut().locals.pop();
+        }
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().function_type == FunctionType::Script {
+            self.parser.error("Can't return from top-level code.");
+        }
+        if self.parser.match_token(Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state().function_type == FunctionType::Initializer {
+                self.parser
+                    .error("Can't return a value from an initializer.");
+            }
+            self.expression();
+            self.parser
+                .consume(Semicolon, "Expected ; after return value.");
+            self.emit_byte(OpReturn.into());
+        }
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.parser.consume(Semicolon, "Expected ; after value.");
+        self.emit_byte(OpPrint.into());
+    }
+
+    fn expression_statement(&mut self) {
         self.expression();
-        self.parser.consume(Eof, "Expected end of expression.");
-        self.end_compiler();
-        return !self.parser.had_error;
+        self.parser
+            .consume(Semicolon, "Expected ; after expression.");
+        self.emit_byte(OpPop.into());
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        self.chunk
-            .write(byte, self.parser.prev.as_ref().unwrap().line);
+        let token = *self.parser.prev.as_ref().unwrap();
+        self.emit_byte_at(byte, &token);
+    }
+
+    // Attributes `byte` to `token` rather than the token parsed last, so
+    // runtime errors point at an operator instead of its right operand.
+    fn emit_byte_at(&mut self, byte: u8, token: &Token) {
+        self.current_chunk().write(byte, token.line, token.span());
+    }
+
+    fn emit_bytes_at(&mut self, byte1: u8, byte2: u8, token: &Token) {
+        self.emit_byte_at(byte1, token);
+        self.emit_byte_at(byte2, token);
+    }
+
+    fn emit_long(&mut self, num: u16) {
+        let token = *self.parser.prev.as_ref().unwrap();
+        self.current_chunk()
+            .write_long(num, token.line, token.span());
+    }
+
+    fn emit_jump(&mut self, instruction: u8) -> usize {
+        self.emit_byte(instruction);
+        self.emit_long(u16::MAX);
+        self.current_chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump offset itself.
+        let jump = self.current_chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.parser.error("Too much code to jump over.");
+        }
+        self.current_chunk().patch_long(offset, jump as u16);
     }
 
-    fn end_compiler(&mut self) {
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpLoop.into());
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.parser.error("Loop body too large.");
+        }
+        self.emit_long(offset as u16);
+    }
+
+    fn emit_return(&mut self) {
+        if self.state().function_type == FunctionType::Initializer {
+            self.emit_bytes(OpGetLocal.into(), 0);
+        } else {
+            self.emit_byte(OpNil.into());
+        }
         self.emit_byte(OpReturn.into());
+    }
+
+    fn end_compiler(&mut self) -> Gc<ObjFunction> {
+        self.emit_return();
+        let function = self.states.pop().unwrap().function;
         if !self.parser.had_error {
-            self.chunk.disassemble("code");
+            let name = function.name.map_or("<script>", |_| function.name());
+            function.chunk.disassemble(name);
         }
+        obj::new_function(self.vm, function)
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -519,81 +883,310 @@ impl<'source_lifetime> Compiler<'source_lifetime> {
         self.emit_byte(byte2);
     }
 
-    fn grouping(comp: &mut Compiler) {
+    fn grouping(comp: &mut Compiler, _can_assign: bool) {
         comp.expression();
         comp.parser
             .consume(RightParen, "Expected ) after expression.");
     }
 
-    // fn choose(comp: &mut Compiler) {
-    //     comp.expression();
-    //     comp.parse_precedence(PrecTernary);
-    // }
+    // cond ? a : b, right-associative, only the selected branch is evaluated.
+    fn choose(comp: &mut Compiler, _can_assign: bool) {
+        let else_jump = comp.emit_jump(OpJumpIfFalse.into());
+        comp.emit_byte(OpPop.into());
+        comp.parse_precedence(PrecTernary);
+        comp.parser.consume(
+            Colon,
+            "Expected : after then branch of conditional expression.",
+        );
+
+        let end_jump = comp.emit_jump(OpJump.into());
+        comp.patch_jump(else_jump);
+        comp.emit_byte(OpPop.into());
+        comp.parse_precedence(PrecTernary);
+        comp.patch_jump(end_jump);
+    }
+
+    fn call(comp: &mut Compiler, _can_assign: bool) {
+        let paren = *comp.parser.prev.as_ref().unwrap();
+        let arg_count = comp.argument_list();
+        comp.emit_bytes_at(OpCall.into(), arg_count, &paren);
+    }
 
-    fn unary(comp: &mut Compiler) {
-        let operator_type = comp.parser.prev.as_ref().unwrap().token_type;
+    fn dot(comp: &mut Compiler, can_assign: bool) {
+        comp.parser
+            .consume(Identifier, "Expected property name after '.'.");
+        let token = *comp.parser.prev.as_ref().unwrap();
+        let name = comp.identifier_constant(token.lexeme);
+
+        if can_assign && comp.parser.match_token(Equal) {
+            comp.expression();
+            comp.emit_bytes_at(OpSetProperty.into(), name, &token);
+        } else if let Some(op) = comp.match_compound_assign(can_assign) {
+            let operator = *comp.parser.prev.as_ref().unwrap();
+            comp.emit_byte(OpDup.into());
+            comp.emit_bytes_at(OpGetProperty.into(), name, &token);
+            comp.expression();
+            comp.emit_byte_at(op.into(), &operator);
+            comp.emit_bytes_at(OpSetProperty.into(), name, &token);
+        } else if comp.parser.match_token(LeftParen) {
+            let arg_count = comp.argument_list();
+            comp.emit_bytes_at(OpInvoke.into(), name, &token);
+            comp.emit_byte_at(arg_count, &token);
+        } else {
+            comp.emit_bytes_at(OpGetProperty.into(), name, &token);
+        }
+    }
+
+    // [a, b, c]
+    fn list(comp: &mut Compiler, _can_assign: bool) {
+        let mut count = 0;
+        if !comp.parser.check(RightBracket) {
+            loop {
+                comp.expression();
+                if count == ELEMENTS_MAX {
+                    comp.parser
+                        .error("Can't have more than 255 elements in a list literal.");
+                }
+                count += 1;
+                if !comp.parser.match_token(Comma) {
+                    break;
+                }
+            }
+        }
+        comp.parser
+            .consume(RightBracket, "Expected ] after list elements.");
+        comp.emit_bytes(OpBuildList.into(), count as u8);
+    }
+
+    // A `{` only starts a map where an expression is expected; at the start
+    // of a statement it is still a block.
+    fn map(comp: &mut Compiler, _can_assign: bool) {
+        let mut count = 0;
+        if !comp.parser.check(RightBrace) {
+            loop {
+                comp.expression();
+                comp.parser.consume(Colon, "Expected : after map key.");
+                comp.expression();
+                if count == ELEMENTS_MAX {
+                    comp.parser
+                        .error("Can't have more than 255 entries in a map literal.");
+                }
+                count += 1;
+                if !comp.parser.match_token(Comma) {
+                    break;
+                }
+            }
+        }
+        comp.parser
+            .consume(RightBrace, "Expected } after map entries.");
+        comp.emit_bytes(OpBuildMap.into(), count as u8);
+    }
+
+    fn index(comp: &mut Compiler, can_assign: bool) {
+        let bracket = *comp.parser.prev.as_ref().unwrap();
+        comp.expression();
+        comp.parser.consume(RightBracket, "Expected ] after index.");
+
+        if can_assign && comp.parser.match_token(Equal) {
+            comp.expression();
+            comp.emit_byte_at(OpIndexSet.into(), &bracket);
+        } else if let Some(op) = comp.match_compound_assign(can_assign) {
+            let operator = *comp.parser.prev.as_ref().unwrap();
+            // Keep the target and index around for the store so neither is
+            // evaluated twice.
+            comp.emit_byte(OpDup2.into());
+            comp.emit_byte_at(OpIndexGet.into(), &bracket);
+            comp.expression();
+            comp.emit_byte_at(op.into(), &operator);
+            comp.emit_byte_at(OpIndexSet.into(), &bracket);
+        } else {
+            comp.emit_byte_at(OpIndexGet.into(), &bracket);
+        }
+    }
+
+    fn this(comp: &mut Compiler, _can_assign: bool) {
+        if comp.classes.is_empty() {
+            comp.parser.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        comp.named_variable("this", false);
+    }
+
+    fn super_(comp: &mut Compiler, _can_assign: bool) {
+        match comp.classes.last() {
+            None => comp.parser.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => comp
+                .parser
+                .error("Can't use 'super' in a class with no superclass."),
+            _ => {}
+        }
+
+        comp.parser
+            .consume(TokenType::Dot, "Expected '.' after 'super'.");
+        comp.parser
+            .consume(Identifier, "Expected superclass method name.");
+        let token = *comp.parser.prev.as_ref().unwrap();
+        let name = comp.identifier_constant(token.lexeme);
+
+        comp.named_variable("this", false);
+        if comp.parser.match_token(LeftParen) {
+            let arg_count = comp.argument_list();
+            comp.named_variable("super", false);
+            comp.emit_bytes_at(OpSuperInvoke.into(), name, &token);
+            comp.emit_byte_at(arg_count, &token);
+        } else {
+            comp.named_variable("super", false);
+            comp.emit_bytes_at(OpGetSuper.into(), name, &token);
+        }
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count = 0;
+        if !self.parser.check(RightParen) {
+            loop {
+                self.expression();
+                if arg_count == ARGS_MAX {
+                    self.parser.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.parser.match_token(Comma) {
+                    break;
+                }
+            }
+        }
+        self.parser
+            .consume(RightParen, "Expected ) after arguments.");
+        arg_count as u8
+    }
+
+    fn unary(comp: &mut Compiler, _can_assign: bool) {
+        let operator = *comp.parser.prev.as_ref().unwrap();
         comp.parse_precedence(PrecUnary);
-        match operator_type {
-            TokenType::Minus => comp.emit_byte(OpNegate.into()),
-            TokenType::Bang => comp.emit_byte(OpNot.into()),
+        match operator.token_type {
+            TokenType::Minus => comp.emit_byte_at(OpNegate.into(), &operator),
+            TokenType::Bang => comp.emit_byte_at(OpNot.into(), &operator),
             _ => {}
         }
     }
 
-    fn string(comp: &mut Compiler) {
-        let obj = obj::copy_string(comp.parser.prev.as_ref().unwrap().lexeme.trim_matches('"'));
-        comp.emit_constant(Value::Obj(obj));
+    fn string(comp: &mut Compiler, _can_assign: bool) {
+        let lexeme = comp.parser.prev.as_ref().unwrap().lexeme;
+        let obj = obj::copy_string(comp.vm, lexeme.trim_matches('"'));
+        comp.emit_constant(Value::obj(obj));
+    }
+
+    fn variable(comp: &mut Compiler, can_assign: bool) {
+        let name = comp.parser.prev.as_ref().unwrap().lexeme;
+        comp.named_variable(name, can_assign);
+    }
+
+    fn named_variable(&mut self, name: &str, can_assign: bool) {
+        let token = *self.parser.prev.as_ref().unwrap();
+        let state = self.states.len() - 1;
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(state, name) {
+            (OpGetLocal, OpSetLocal, slot)
+        } else if let Some(index) = self.resolve_upvalue(state, name) {
+            (OpGetUpvalue, OpSetUpvalue, index)
+        } else {
+            (OpGetGlobal, OpSetGlobal, self.identifier_constant(name))
+        };
+        if can_assign && self.parser.match_token(Equal) {
+            self.expression();
+            self.emit_bytes_at(set_op.into(), arg, &token);
+        } else if let Some(op) = self.match_compound_assign(can_assign) {
+            let operator = *self.parser.prev.as_ref().unwrap();
+            self.emit_bytes_at(get_op.into(), arg, &token);
+            self.expression();
+            self.emit_byte_at(op.into(), &operator);
+            self.emit_bytes_at(set_op.into(), arg, &token);
+        } else {
+            self.emit_bytes_at(get_op.into(), arg, &token);
+        }
     }
 
-    fn binary(comp: &mut Compiler) {
-        let operator_type = comp.parser.prev.as_ref().unwrap().token_type;
+    // Consumes a compound assignment operator and returns the arithmetic
+    // instruction it applies to the target.
+    fn match_compound_assign(&mut self, can_assign: bool) -> Option<OpCode> {
+        if !can_assign {
+            return None;
+        }
+        let op = match self.parser.current.as_ref().unwrap().token_type {
+            TokenType::PlusEqual => OpAdd,
+            TokenType::MinusEqual => OpSubtract,
+            TokenType::StarEqual => OpMultiply,
+            TokenType::SlashEqual => OpDivide,
+            _ => return None,
+        };
+        self.parser.advance();
+        Some(op)
+    }
+
+    fn binary(comp: &mut Compiler, _can_assign: bool) {
+        let operator = *comp.parser.prev.as_ref().unwrap();
+        let operator_type = operator.token_type;
         let ind: u8 = operator_type.into();
         let rule: &ParseRule = &Compiler::PARSE_RULES[ind as usize];
         let prec: u8 = rule.precedence.into();
         comp.parse_precedence(unsafe { Precedence::from_unchecked(prec + 1) });
         match operator_type {
-            TokenType::Plus => comp.emit_byte(OpAdd.into()),
-            TokenType::Minus => comp.emit_byte(OpSubtract.into()),
-            TokenType::Star => comp.emit_byte(OpMultiply.into()),
-            TokenType::Slash => comp.emit_byte(OpDivide.into()),
-            TokenType::BangEqual => comp.emit_byte(OpNotEqual.into()),
-            TokenType::EqualEqual => comp.emit_byte(OpEqual.into()),
-            TokenType::Greater => comp.emit_byte(OpGreater.into()),
-            TokenType::GreaterEqual => comp.emit_byte(OpGreaterEqual.into()),
-            TokenType::Less => comp.emit_byte(OpLess.into()),
-            TokenType::LessEqual => comp.emit_byte(OpLessEqual.into()),
+            TokenType::Plus => comp.emit_byte_at(OpAdd.into(), &operator),
+            TokenType::Minus => comp.emit_byte_at(OpSubtract.into(), &operator),
+            TokenType::Star => comp.emit_byte_at(OpMultiply.into(), &operator),
+            TokenType::Slash => comp.emit_byte_at(OpDivide.into(), &operator),
+            TokenType::BangEqual => comp.emit_byte_at(OpNotEqual.into(), &operator),
+            TokenType::EqualEqual => comp.emit_byte_at(OpEqual.into(), &operator),
+            TokenType::Greater => comp.emit_byte_at(OpGreater.into(), &operator),
+            TokenType::GreaterEqual => comp.emit_byte_at(OpGreaterEqual.into(), &operator),
+            TokenType::Less => comp.emit_byte_at(OpLess.into(), &operator),
+            TokenType::LessEqual => comp.emit_byte_at(OpLessEqual.into(), &operator),
             _ => {}
         }
     }
 
+    fn and(comp: &mut Compiler, _can_assign: bool) {
+        let end_jump = comp.emit_jump(OpJumpIfFalse.into());
+        comp.emit_byte(OpPop.into());
+        comp.parse_precedence(PrecAnd);
+        comp.patch_jump(end_jump);
+    }
+
+    fn or(comp: &mut Compiler, _can_assign: bool) {
+        let else_jump = comp.emit_jump(OpJumpIfFalse.into());
+        let end_jump = comp.emit_jump(OpJump.into());
+        comp.patch_jump(else_jump);
+        comp.emit_byte(OpPop.into());
+        comp.parse_precedence(PrecOr);
+        comp.patch_jump(end_jump);
+    }
+
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.parser.advance();
         // println!("parser is {:?} {:?}", self.parser.prev, self.parser.current);
         let ind: u8 = self.parser.prev.as_ref().unwrap().token_type.into();
         // println!("token type should be {:?} and is {:?}", self.parser.prev.as_ref().unwrap().token_type, ind);
-        // println!(
-        //     "precedence for {:?} is {}",
-        //     precedence,
-        //     Compiler::PARSE_RULES_DBG[ind as usize]
-        // );
         let rule: &ParseRule = &Compiler::PARSE_RULES[ind as usize];
-        if rule.prefix.is_none() {
-            self.parser.error("Expected expression.");
-        } else {
-            rule.prefix.unwrap()(self);
-            //println!("{:?}", self.chunk.get_constant(0));
-            // println!("will check precedence for {:?}", precedence);
-            while self.is_lower_prec(precedence) {
-                self.parser.advance();
-                // println!("parser is {:?} {:?}", self.parser.prev, self.parser.current);
-                let ind: u8 = self.parser.prev.as_ref().unwrap().token_type.into();
-                // println!(
-                //     "precedence for {:?} is {}",
-                //     precedence,
-                //     Compiler::PARSE_RULES_DBG[ind as usize]
-                // );
-                let pr: &ParseRule = &Compiler::PARSE_RULES[ind as usize];
-                pr.infix.unwrap()(self);
+        match rule.prefix {
+            None => self.parser.error("Expected expression."),
+            Some(prefix) => {
+                let prec: u8 = precedence.into();
+                let can_assign = prec <= PrecAssignment.into();
+                prefix(self, can_assign);
+                //println!("{:?}", self.chunk.get_constant(0));
+                // println!("will check precedence for {:?}", precedence);
+                while self.is_lower_prec(precedence) {
+                    self.parser.advance();
+                    // println!("parser is {:?} {:?}", self.parser.prev, self.parser.current);
+                    let ind: u8 = self.parser.prev.as_ref().unwrap().token_type.into();
+                    let pr: &ParseRule = &Compiler::PARSE_RULES[ind as usize];
+                    pr.infix.unwrap()(self, can_assign);
+                }
+
+                if can_assign
+                    && (self.parser.match_token(Equal)
+                        || self.match_compound_assign(can_assign).is_some())
+                {
+                    self.parser.error("Invalid assignment target.");
+                }
             }
         }
     }
@@ -610,7 +1203,7 @@ impl<'source_lifetime> Compiler<'source_lifetime> {
         self.parse_precedence(PrecAssignment);
     }
 
-    fn number(comp: &mut Compiler) {
+    fn number(comp: &mut Compiler, _can_assign: bool) {
         let value = comp
             .parser
             .prev
@@ -619,15 +1212,15 @@ impl<'source_lifetime> Compiler<'source_lifetime> {
             .lexeme
             .parse::<f64>()
             .unwrap();
-        comp.emit_constant(Value::Number(value));
+        comp.emit_constant(Value::number(value));
     }
 
-    fn literal(comp: &mut Compiler) {
+    fn literal(comp: &mut Compiler, _can_assign: bool) {
         match comp.parser.prev.as_ref().unwrap().token_type {
             TokenType::False => comp.emit_byte(OpFalse.into()),
             TokenType::True => comp.emit_byte(OpTrue.into()),
             TokenType::Nil => comp.emit_byte(OpNil.into()),
-            _ => return,
+            _ => {}
         }
     }
 
@@ -637,12 +1230,14 @@ impl<'source_lifetime> Compiler<'source_lifetime> {
     }
 
     fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = self.chunk.add_constant(value);
+        // Constants are unreachable until their function is allocated.
+        self.vm.push_compiler_root(value);
+        let constant = self.current_chunk().add_constant(value);
         if constant > 255 {
             self.parser.error("Can not have more than 255 constants");
             0
         } else {
-            constant.into()
+            constant as u8
         }
     }
 }
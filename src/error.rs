@@ -0,0 +1,154 @@
+use std::error::Error;
+use std::fmt;
+
+// A byte range of the source.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+}
+
+// Returns the 1-based column of byte `offset`, counted in characters, and
+// the text of its line.
+pub fn line_at(source: &str, offset: usize) -> (usize, &str) {
+    let start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |i| offset + i);
+    let column = source[start..offset].chars().count() + 1;
+    (column, source[start..end].trim_end_matches('\r'))
+}
+
+// Renders `source_line` with carets under `len` bytes from `column`, in the
+// style of rustc.
+fn snippet(line: i32, column: usize, len: usize, source_line: &str) -> String {
+    let gutter = " ".repeat(line.to_string().len());
+    let start = source_line
+        .char_indices()
+        .nth(column - 1)
+        .map_or(source_line.len(), |(i, _)| i);
+    // Keep tabs so the carets line up however wide the terminal draws them.
+    let pad: String = source_line[..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    // Spans that run past the end of the line are cut off there.
+    let end = (start + len).min(source_line.len());
+    let carets = "^".repeat(source_line[start..end].chars().count().max(1));
+    format!(
+        "{0}--> {1}:{2}\n{0} |\n{1} | {3}\n{0} | {4}{5}",
+        gutter, line, column, source_line, pad, carets
+    )
+}
+
+// A syntax error. Columns here and in `StackFrame` are 1-based and count
+// characters. `lexeme` is empty when the error is at the end of the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub line: i32,
+    pub column: usize,
+    pub lexeme: String,
+    pub message: String,
+    pub span: Span,
+    pub source_line: String,
+}
+
+impl CompileError {
+    pub fn render(&self) -> String {
+        let snippet = snippet(self.line, self.column, self.span.len, &self.source_line);
+        format!("error: {}\n{}", self.message, snippet)
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[line {}] Error", self.line)?;
+        if self.lexeme.is_empty() {
+            write!(f, " at end")?;
+        } else {
+            write!(f, " at {}", self.lexeme)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl Error for CompileError {}
+
+// A call that was active when a runtime error was raised.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    pub line: i32,
+    pub column: usize,
+    // None for the top-level script.
+    pub function: Option<String>,
+    // The code being run in this frame.
+    pub span: Span,
+    pub source_line: String,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
+// The stack trace is innermost call first. Native functions raise errors
+// with an empty trace and the VM fills it in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub stack_trace: Vec<StackFrame>,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> Self {
+        RuntimeError {
+            message: message.into(),
+            stack_trace: vec![],
+        }
+    }
+
+    // The message, a snippet of where it was raised and the stack trace.
+    pub fn render(&self) -> String {
+        let mut rendered = format!("error: {}", self.message);
+        if let Some(frame) = self.stack_trace.first() {
+            let (line, column) = (frame.line, frame.column);
+            rendered.push('\n');
+            rendered.push_str(&snippet(line, column, frame.span.len, &frame.source_line));
+        }
+        for frame in self.stack_trace.iter() {
+            rendered.push_str(&format!("\n{}", frame));
+        }
+        rendered
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for RuntimeError {}
+
+// Receives every error `VM::interpret` runs into, before it is returned.
+pub trait ErrorReporter {
+    fn compile_error(&mut self, error: &CompileError);
+    fn runtime_error(&mut self, error: &RuntimeError);
+}
+
+// The default reporter, printing rendered errors to stderr.
+pub struct StderrReporter;
+
+impl ErrorReporter for StderrReporter {
+    fn compile_error(&mut self, error: &CompileError) {
+        eprintln!("{}", error.render());
+    }
+
+    fn runtime_error(&mut self, error: &RuntimeError) {
+        eprintln!("{}", error.render());
+    }
+}
@@ -1,15 +1,451 @@
-use std::alloc::Layout;
+use crate::obj::{
+    ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjList, ObjMap, ObjNative,
+    ObjString, ObjType, ObjUpvalue,
+};
+use crate::table::Table;
+use crate::value::Value;
+use std::cell::Cell;
+use std::ops::Deref;
+use std::ptr::NonNull;
 
-pub struct GC {}
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+// This is the only module that dereferences raw object pointers. The rest of
+// the interpreter goes through `ObjRef` and `Gc<T>`, which stay sound as long
+// as these invariants hold:
+//
+// 1. Every object lives in a `GcBox<T>` allocated by `GC::alloc`. `GcBox` is
+//    `repr(C)` with the header first, so a pointer to the box is also a valid
+//    pointer to its `ObjHeader`.
+// 2. `ObjHeader::obj_type` is `T::TYPE` of the box it heads. It is written once
+//    in `GC::alloc` and never changes, so casting an `ObjRef` to `Gc<T>` after
+//    checking the tag is a cast back to the original type.
+// 3. Objects are freed only by `GC::sweep` and `GC::free_objects`. A handle is
+//    valid while its object is reachable from the VM's roots, so callers must
+//    root an object (e.g. push it on the stack) before the next allocation.
+// 4. Handles are Copy, so they only hand out shared references. State the VM
+//    changes after allocation lives in `Cell`s and `RefCell`s inside the
+//    object, which keeps two handles from ever aliasing a `&mut`.
+// 5. With the nan-boxing feature, `ObjRef::from_bits` is only given bits that
+//    came from `ObjRef::to_bits`, which is how value.rs unpacks objects.
+
+// Bookkeeping shared by every heap object.
+pub struct ObjHeader {
+    obj_type: ObjType,
+    marked: Cell<bool>,
+    // Next object in the list of every object allocated by the GC.
+    next: Cell<Option<ObjRef>>,
+}
+
+#[repr(C)]
+struct GcBox<T> {
+    header: ObjHeader,
+    value: T,
+}
+
+/// A type that can live on the GC heap.
+///
+/// # Safety
+///
+/// `TYPE` must be unique to the implementing type, since `ObjRef::downcast`
+/// trusts the tag to cast back to it.
+pub unsafe trait Object: 'static {
+    const TYPE: ObjType;
+}
+
+unsafe impl Object for ObjString {
+    const TYPE: ObjType = ObjType::OString;
+}
+
+unsafe impl Object for ObjFunction {
+    const TYPE: ObjType = ObjType::OFunction;
+}
+
+unsafe impl Object for ObjClosure {
+    const TYPE: ObjType = ObjType::OClosure;
+}
+
+unsafe impl Object for ObjUpvalue {
+    const TYPE: ObjType = ObjType::OUpvalue;
+}
+
+unsafe impl Object for ObjClass {
+    const TYPE: ObjType = ObjType::OClass;
+}
+
+unsafe impl Object for ObjInstance {
+    const TYPE: ObjType = ObjType::OInstance;
+}
+
+unsafe impl Object for ObjBoundMethod {
+    const TYPE: ObjType = ObjType::OBoundMethod;
+}
+
+unsafe impl Object for ObjList {
+    const TYPE: ObjType = ObjType::OList;
+}
+
+unsafe impl Object for ObjMap {
+    const TYPE: ObjType = ObjType::OMap;
+}
+
+unsafe impl Object for ObjNative {
+    const TYPE: ObjType = ObjType::ONative;
+}
+
+// Untyped handle to a heap object, one pointer wide.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ObjRef {
+    ptr: NonNull<ObjHeader>,
+}
+
+impl ObjRef {
+    fn header(&self) -> &ObjHeader {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    pub fn obj_type(self) -> ObjType {
+        self.header().obj_type
+    }
+
+    pub fn is_marked(self) -> bool {
+        self.header().marked.get()
+    }
+
+    pub fn is<T: Object>(self) -> bool {
+        self.obj_type() == T::TYPE
+    }
+
+    pub fn downcast<T: Object>(self) -> Option<Gc<T>> {
+        if self.is::<T>() {
+            Some(Gc {
+                ptr: self.ptr.cast(),
+            })
+        } else {
+            None
+        }
+    }
+
+    // Object addresses fit in the low 48 bits on every platform we target.
+    #[cfg(feature = "nan-boxing")]
+    pub fn to_bits(self) -> u64 {
+        self.ptr.as_ptr().expose_provenance() as u64
+    }
+
+    /// Rebuilds a handle packed with `to_bits`.
+    ///
+    /// # Safety
+    ///
+    /// `bits` must come from `to_bits` on a handle whose object is still live.
+    #[cfg(feature = "nan-boxing")]
+    pub unsafe fn from_bits(bits: u64) -> ObjRef {
+        let ptr = std::ptr::with_exposed_provenance_mut::<ObjHeader>(bits as usize);
+        ObjRef {
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+        }
+    }
+
+    // Downcast for when the type is known from context, e.g. after matching on
+    // obj_type or for constants the compiler always emits as strings.
+    pub fn cast<T: Object>(self) -> Gc<T> {
+        match self.downcast() {
+            Some(o) => o,
+            None => panic!("Expected {:?} but got {:?}.", T::TYPE, self.obj_type()),
+        }
+    }
+}
+
+// Typed handle to a heap object of type `T`.
+pub struct Gc<T: Object> {
+    ptr: NonNull<GcBox<T>>,
+}
+
+impl<T: Object> Gc<T> {
+    pub fn as_obj(self) -> ObjRef {
+        ObjRef {
+            ptr: self.ptr.cast(),
+        }
+    }
+}
+
+impl<T: Object> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: Object> Copy for Gc<T> {}
+
+impl<T: Object> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl<T: Object> Eq for Gc<T> {}
+
+impl<T: Object> From<Gc<T>> for ObjRef {
+    fn from(o: Gc<T>) -> Self {
+        o.as_obj()
+    }
+}
+
+impl<T: Object> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &self.ptr.as_ref().value }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HeapStats {
+    pub objects: usize,
+    pub bytes_allocated: usize,
+    pub collections: usize,
+}
+
+pub struct GC {
+    objects: Option<ObjRef>,
+    gray_stack: Vec<ObjRef>,
+    object_count: usize,
+    bytes_allocated: usize,
+    next_gc: usize,
+    collections: usize,
+}
 
 impl GC {
-    pub fn alloc(layout: Layout) -> *mut u8 {
-        unsafe { std::alloc::alloc(layout) }
+    pub fn new() -> Self {
+        GC {
+            objects: None,
+            gray_stack: vec![],
+            object_count: 0,
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            collections: 0,
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            objects: self.object_count,
+            bytes_allocated: self.bytes_allocated,
+            collections: self.collections,
+        }
+    }
+
+    pub fn alloc<T: Object>(&mut self, obj: T) -> Gc<T> {
+        let boxed = Box::new(GcBox {
+            header: ObjHeader {
+                obj_type: T::TYPE,
+                marked: Cell::new(false),
+                next: Cell::new(self.objects),
+            },
+            value: obj,
+        });
+        let o = Gc {
+            ptr: NonNull::from(Box::leak(boxed)),
+        }
+        .as_obj();
+        self.objects = Some(o);
+        self.object_count += 1;
+        self.bytes_allocated += object_size(o);
+        #[cfg(feature = "gc-log")]
+        println!(
+            "{:p} allocate {} for {:?}",
+            o.ptr,
+            object_size(o),
+            o.obj_type()
+        );
+        o.cast()
+    }
+
+    // Runs `f` on an object whose list or table may reallocate, re-counting its
+    // size so the subtraction in `free_object` stays balanced.
+    pub fn resize<T: Object, R>(&mut self, obj: Gc<T>, f: impl FnOnce(&T) -> R) -> R {
+        let before = object_size(obj.as_obj());
+        let result = f(&obj);
+        self.bytes_allocated = self.bytes_allocated + object_size(obj.as_obj()) - before;
+        result
+    }
+
+    #[cfg(not(feature = "gc-stress"))]
+    pub fn should_collect(&self) -> bool {
+        self.bytes_allocated > self.next_gc
+    }
+
+    #[cfg(feature = "gc-stress")]
+    pub fn should_collect(&self) -> bool {
+        true
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if value.is_obj() {
+            self.mark_object(value.as_obj());
+        }
     }
 
-    pub fn dealloc(ptr: *mut u8, layout: Layout) {
-        unsafe {
-            std::alloc::dealloc(ptr, layout);
+    pub fn mark_object(&mut self, o: impl Into<ObjRef>) {
+        let o = o.into();
+        if o.is_marked() {
+            return;
+        }
+        o.header().marked.set(true);
+        #[cfg(feature = "gc-log")]
+        println!("{:p} mark {:?}", o.ptr, o.obj_type());
+        self.gray_stack.push(o);
+    }
+
+    pub fn mark_table(&mut self, table: &Table) {
+        for (key, value) in table.iter() {
+            self.mark_object(key);
+            self.mark_value(value);
+        }
+    }
+
+    // Blackens gray objects until every object reachable from the roots is
+    // marked.
+    pub fn trace_references(&mut self) {
+        while let Some(o) = self.gray_stack.pop() {
+            self.blacken_object(o);
+        }
+    }
+
+    fn blacken_object(&mut self, o: ObjRef) {
+        match o.obj_type() {
+            ObjType::OString => {}
+            ObjType::OFunction => {
+                let function = o.cast::<ObjFunction>();
+                if let Some(name) = function.name {
+                    self.mark_object(name);
+                }
+                for &constant in function.chunk.constants() {
+                    self.mark_value(constant);
+                }
+            }
+            ObjType::OClosure => {
+                let closure = o.cast::<ObjClosure>();
+                self.mark_object(closure.function);
+                for &upvalue in closure.upvalues.iter() {
+                    self.mark_object(upvalue);
+                }
+            }
+            ObjType::OUpvalue => {
+                if let Some(closed) = o.cast::<ObjUpvalue>().closed.get() {
+                    self.mark_value(closed);
+                }
+            }
+            ObjType::OClass => {
+                let class = o.cast::<ObjClass>();
+                self.mark_object(class.name);
+                self.mark_table(&class.methods.borrow());
+            }
+            ObjType::OInstance => {
+                let instance = o.cast::<ObjInstance>();
+                self.mark_object(instance.class);
+                self.mark_table(&instance.fields.borrow());
+            }
+            ObjType::OBoundMethod => {
+                let bound = o.cast::<ObjBoundMethod>();
+                self.mark_value(bound.receiver);
+                self.mark_object(bound.method);
+            }
+            ObjType::OList => {
+                for &item in o.cast::<ObjList>().items.borrow().iter() {
+                    self.mark_value(item);
+                }
+            }
+            ObjType::OMap => {
+                for (key, value) in o.cast::<ObjMap>().table.borrow().iter() {
+                    self.mark_value(key);
+                    self.mark_value(value);
+                }
+            }
+            ObjType::ONative => self.mark_object(o.cast::<ObjNative>().name),
+        }
+    }
+
+    // Frees every unmarked object and clears the mark on the survivors.
+    pub fn sweep(&mut self) {
+        let mut prev: Option<ObjRef> = None;
+        let mut object = self.objects;
+        while let Some(o) = object {
+            object = o.header().next.get();
+            if o.is_marked() {
+                o.header().marked.set(false);
+                prev = Some(o);
+            } else {
+                match prev {
+                    Some(p) => p.header().next.set(object),
+                    None => self.objects = object,
+                }
+                self.free_object(o);
+            }
+        }
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+        self.collections += 1;
+    }
+
+    fn free_object(&mut self, o: ObjRef) {
+        #[cfg(feature = "gc-log")]
+        println!("{:p} free {} for {:?}", o.ptr, object_size(o), o.obj_type());
+        self.object_count -= 1;
+        self.bytes_allocated -= object_size(o);
+        match o.obj_type() {
+            ObjType::OString => free::<ObjString>(o),
+            ObjType::OFunction => free::<ObjFunction>(o),
+            ObjType::OClosure => free::<ObjClosure>(o),
+            ObjType::OUpvalue => free::<ObjUpvalue>(o),
+            ObjType::OClass => free::<ObjClass>(o),
+            ObjType::OInstance => free::<ObjInstance>(o),
+            ObjType::OBoundMethod => free::<ObjBoundMethod>(o),
+            ObjType::OList => free::<ObjList>(o),
+            ObjType::OMap => free::<ObjMap>(o),
+            ObjType::ONative => free::<ObjNative>(o),
+        }
+    }
+
+    pub fn free_objects(&mut self) {
+        while let Some(o) = self.objects {
+            self.objects = o.header().next.get();
+            self.free_object(o);
+        }
+        self.gray_stack = vec![];
+    }
+}
+
+impl Drop for GC {
+    fn drop(&mut self) {
+        self.free_objects();
+    }
+}
+
+// Only called on objects unlinked from the object list, so no handle to them
+// is reachable any more.
+fn free<T: Object>(o: ObjRef) {
+    let o = o.cast::<T>();
+    unsafe { drop(Box::from_raw(o.ptr.as_ptr())) };
+}
+
+fn object_size(o: ObjRef) -> usize {
+    match o.obj_type() {
+        ObjType::OString => size_of::<GcBox<ObjString>>() + o.cast::<ObjString>().chars.len(),
+        ObjType::OFunction => size_of::<GcBox<ObjFunction>>(),
+        ObjType::OClosure => size_of::<GcBox<ObjClosure>>(),
+        ObjType::OUpvalue => size_of::<GcBox<ObjUpvalue>>(),
+        ObjType::OClass => {
+            size_of::<GcBox<ObjClass>>() + o.cast::<ObjClass>().methods.borrow().heap_size()
+        }
+        ObjType::OInstance => {
+            size_of::<GcBox<ObjInstance>>() + o.cast::<ObjInstance>().fields.borrow().heap_size()
+        }
+        ObjType::OBoundMethod => size_of::<GcBox<ObjBoundMethod>>(),
+        ObjType::OList => {
+            let items = o.cast::<ObjList>().items.borrow().capacity();
+            size_of::<GcBox<ObjList>>() + items * size_of::<Value>()
         }
+        ObjType::OMap => size_of::<GcBox<ObjMap>>() + o.cast::<ObjMap>().table.borrow().heap_size(),
+        ObjType::ONative => size_of::<GcBox<ObjNative>>(),
     }
 }
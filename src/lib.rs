@@ -0,0 +1,29 @@
+//! A bytecode virtual machine for Lox that can be embedded in Rust programs.
+//!
+//! Create a `VM`, expose host functions with `VM::define_native` and globals
+//! with `VM::set_global`, run source with `VM::interpret`, then read results
+//! back with `VM::get_global` or call into Lox with `VM::call_function`.
+//! Values cross the boundary as `LoxValue`s: nil, booleans, numbers and
+//! strings are copied, and other objects are passed as `Handle`s that keep
+//! them alive until dropped.
+//! Errors are returned from `VM::interpret` and also passed to the VM's
+//! `ErrorReporter`, which prints to stderr unless replaced.
+
+mod chunk;
+mod common;
+mod compiler;
+mod error;
+mod gc;
+mod native;
+mod obj;
+mod parser;
+mod scanner;
+mod table;
+mod value;
+mod vm;
+
+pub use error::{CompileError, ErrorReporter, RuntimeError, Span, StackFrame, StderrReporter};
+pub use gc::HeapStats;
+pub use obj::NativeFn;
+pub use value::{Handle, LoxValue};
+pub use vm::{InterpretResult, VM};
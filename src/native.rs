@@ -0,0 +1,12 @@
+use crate::error::RuntimeError;
+use crate::value::LoxValue;
+use crate::vm::VM;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Seconds since the Unix epoch, for timing scripts.
+pub fn clock(_vm: &mut VM, _args: &[LoxValue]) -> Result<LoxValue, RuntimeError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| RuntimeError::new("System clock is set before the Unix epoch."))?;
+    Ok(LoxValue::Number(now.as_secs_f64()))
+}
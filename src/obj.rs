@@ -1,61 +1,282 @@
-use crate::{gc::GC, value::Value};
-use std::alloc::Layout;
-use std::mem;
+use std::cell::{Cell, RefCell};
 
-pub trait Object {
-    fn get_type(&self) -> ObjType;
-}
+use crate::{
+    chunk::Chunk,
+    error::RuntimeError,
+    gc::{Gc, ObjRef},
+    table::{Table, ValueTable},
+    value::{print_value, LoxValue, Value},
+    vm::VM,
+};
 
-#[derive(Debug, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ObjType {
     OString,
+    OFunction,
+    OClosure,
+    OUpvalue,
+    OClass,
+    OInstance,
+    OBoundMethod,
+    OList,
+    OMap,
+    ONative,
 }
 
 pub struct ObjString {
-    pub chars: *mut u8,
-    pub len: usize,
+    pub chars: Box<str>,
+    pub hash: u32,
 }
 
-impl Object for ObjString {
-    fn get_type(&self) -> ObjType {
-        ObjType::OString
+impl ObjString {
+    pub fn as_str(&self) -> &str {
+        &self.chars
     }
 }
 
-pub fn is_obj_type(v: Value, obj_type: ObjType) -> bool {
-    match v {
-        Value::Bool(_) => false,
-        Value::Number(_) => false,
-        Value::Obj(o) => unsafe { (*o).get_type() == obj_type },
-        Value::Empty => false,
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: Option<Gc<ObjString>>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<Gc<ObjString>>) -> Self {
+        ObjFunction {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+        }
     }
+
+    pub fn name(&self) -> &str {
+        self.name.as_ref().map_or("script", |n| n.as_str())
+    }
+}
+
+pub struct ObjClosure {
+    pub function: Gc<ObjFunction>,
+    pub upvalues: Vec<Gc<ObjUpvalue>>,
 }
 
-pub fn print_obj(obj: *const dyn Object) -> () {
-    unsafe {
-        match (*obj).get_type() {
-            ObjType::OString => {
-                let o = (obj as *const ObjString).read();
-                let utf = std::slice::from_raw_parts_mut(o.chars, o.len);
-                println!("{:?}", std::str::from_utf8(utf).unwrap());
-            }
+// Objects are shared through Copy handles, so the parts the VM mutates after
+// allocation sit behind cells.
+pub struct ObjUpvalue {
+    // Index of the captured variable in VM::stack while the upvalue is open.
+    pub location: usize,
+    pub closed: Cell<Option<Value>>,
+    // Next open upvalue, sorted by descending stack location.
+    pub next: Cell<Option<Gc<ObjUpvalue>>>,
+}
+
+pub struct ObjClass {
+    pub name: Gc<ObjString>,
+    pub methods: RefCell<Table>,
+}
+
+pub struct ObjInstance {
+    pub class: Gc<ObjClass>,
+    pub fields: RefCell<Table>,
+}
+
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: Gc<ObjClosure>,
+}
+
+pub struct ObjList {
+    pub items: RefCell<Vec<Value>>,
+}
+
+pub struct ObjMap {
+    pub table: RefCell<ValueTable>,
+}
+
+// A host function callable from Lox. Objects other than strings arrive as
+// handles, which the native can read or pass back through the VM.
+pub type NativeFn = fn(&mut VM, &[LoxValue]) -> Result<LoxValue, RuntimeError>;
+
+pub struct ObjNative {
+    pub name: Gc<ObjString>,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+pub fn print_obj(obj: ObjRef) {
+    print_nested(obj, &mut vec![]);
+}
+
+// `enclosing` holds the lists and maps already being printed around `obj`, so
+// a container that holds itself prints as [...] or {...} rather than
+// recursing forever.
+fn print_nested(obj: ObjRef, enclosing: &mut Vec<ObjRef>) {
+    match obj.obj_type() {
+        ObjType::OString => print!("{}", obj.cast::<ObjString>().as_str()),
+        ObjType::OFunction => print_function(&obj.cast::<ObjFunction>()),
+        ObjType::OClosure => print_function(&obj.cast::<ObjClosure>().function),
+        ObjType::OUpvalue => print!("upvalue"),
+        ObjType::OClass => print!("{}", obj.cast::<ObjClass>().name.as_str()),
+        ObjType::OInstance => {
+            let class = obj.cast::<ObjInstance>().class;
+            print!("{} instance", class.name.as_str())
         }
+        ObjType::OBoundMethod => print_function(&obj.cast::<ObjBoundMethod>().method.function),
+        ObjType::OList => print_list(obj.cast::<ObjList>(), enclosing),
+        ObjType::OMap => print_map(obj.cast::<ObjMap>(), enclosing),
+        ObjType::ONative => print!("<native fn {}>", obj.cast::<ObjNative>().name.as_str()),
     }
 }
 
-pub fn copy_string(s: &str) -> *mut ObjString {
-    let chars = GC::alloc(Layout::array::<u8>(s.len()).unwrap());
-    unsafe {
-        std::ptr::copy::<u8>(s.as_bytes().as_ptr(), chars, s.len());
+fn print_list(list: Gc<ObjList>, enclosing: &mut Vec<ObjRef>) {
+    if enclosing.contains(&list.as_obj()) {
+        print!("[...]");
+        return;
     }
-    allocate_string(chars, s.len())
+    enclosing.push(list.as_obj());
+    print!("[");
+    for (i, &item) in list.items.borrow().iter().enumerate() {
+        if i > 0 {
+            print!(", ");
+        }
+        print_item(item, enclosing);
+    }
+    print!("]");
+    enclosing.pop();
+}
+
+fn print_map(map: Gc<ObjMap>, enclosing: &mut Vec<ObjRef>) {
+    if enclosing.contains(&map.as_obj()) {
+        print!("{{...}}");
+        return;
+    }
+    enclosing.push(map.as_obj());
+    print!("{{");
+    for (i, (key, value)) in map.table.borrow().iter().enumerate() {
+        if i > 0 {
+            print!(", ");
+        }
+        print_item(key, enclosing);
+        print!(": ");
+        print_item(value, enclosing);
+    }
+    print!("}}");
+    enclosing.pop();
+}
+
+fn print_item(item: Value, enclosing: &mut Vec<ObjRef>) {
+    if item.is_obj() {
+        print_nested(item.as_obj(), enclosing);
+    } else {
+        print_value(item);
+    }
+}
+
+fn print_function(f: &ObjFunction) {
+    match f.name {
+        Some(_) => print!("<fn {}>", f.name()),
+        None => print!("<script>"),
+    }
+}
+
+// FNV-1a over the string's bytes.
+pub fn hash_string(chars: &[u8]) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for &c in chars {
+        hash ^= c as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+pub fn copy_string(vm: &mut VM, s: &str) -> Gc<ObjString> {
+    let hash = hash_string(s.as_bytes());
+    match vm.find_string(s, hash) {
+        Some(interned) => interned,
+        None => allocate_string(vm, s.into(), hash),
+    }
+}
+
+pub fn take_string(vm: &mut VM, chars: String) -> Gc<ObjString> {
+    let hash = hash_string(chars.as_bytes());
+    match vm.find_string(&chars, hash) {
+        Some(interned) => interned,
+        None => allocate_string(vm, chars.into_boxed_str(), hash),
+    }
+}
+
+fn allocate_string(vm: &mut VM, chars: Box<str>, hash: u32) -> Gc<ObjString> {
+    let string = vm.alloc(ObjString { chars, hash });
+    vm.intern_string(string);
+    string
+}
+
+// Functions are built up by the compiler and only allocated once complete.
+pub fn new_function(vm: &mut VM, function: ObjFunction) -> Gc<ObjFunction> {
+    vm.alloc(function)
+}
+
+pub fn new_closure(
+    vm: &mut VM,
+    function: Gc<ObjFunction>,
+    upvalues: Vec<Gc<ObjUpvalue>>,
+) -> Gc<ObjClosure> {
+    vm.alloc(ObjClosure { function, upvalues })
+}
+
+pub fn new_upvalue(vm: &mut VM, location: usize, next: Option<Gc<ObjUpvalue>>) -> Gc<ObjUpvalue> {
+    vm.alloc(ObjUpvalue {
+        location,
+        closed: Cell::new(None),
+        next: Cell::new(next),
+    })
+}
+
+pub fn new_class(vm: &mut VM, name: Gc<ObjString>) -> Gc<ObjClass> {
+    vm.alloc(ObjClass {
+        name,
+        methods: RefCell::new(Table::new()),
+    })
+}
+
+pub fn new_instance(vm: &mut VM, class: Gc<ObjClass>) -> Gc<ObjInstance> {
+    vm.alloc(ObjInstance {
+        class,
+        fields: RefCell::new(Table::new()),
+    })
+}
+
+pub fn new_bound_method(
+    vm: &mut VM,
+    receiver: Value,
+    method: Gc<ObjClosure>,
+) -> Gc<ObjBoundMethod> {
+    vm.alloc(ObjBoundMethod { receiver, method })
+}
+
+pub fn new_list(vm: &mut VM, items: Vec<Value>) -> Gc<ObjList> {
+    vm.alloc(ObjList {
+        items: RefCell::new(items),
+    })
+}
+
+pub fn new_native(
+    vm: &mut VM,
+    name: Gc<ObjString>,
+    arity: usize,
+    function: NativeFn,
+) -> Gc<ObjNative> {
+    vm.alloc(ObjNative {
+        name,
+        arity,
+        function,
+    })
 }
 
-pub fn allocate_string(chars: *mut u8, len: usize) -> *mut ObjString {
-    let os = GC::alloc(Layout::new::<ObjString>()) as *mut ObjString;
-    unsafe {
-        (*os).len = len;
-        (*os).chars = chars;
-    };
-    os
+pub fn new_map(vm: &mut VM) -> Gc<ObjMap> {
+    vm.alloc(ObjMap {
+        table: RefCell::new(ValueTable::new()),
+    })
 }
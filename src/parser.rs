@@ -1,5 +1,6 @@
 use crate::common::TokenType;
-use crate::common::TokenType::Eof;
+use crate::common::TokenType::{Class, Eof, For, Fun, If, Print, Return, Semicolon, Var, While};
+use crate::error::{line_at, CompileError};
 use crate::scanner::{Scanner, Token};
 use num_enum::IntoPrimitive;
 use num_enum::UnsafeFromPrimitive;
@@ -9,9 +10,11 @@ pub struct Parser<'source_lifetime> {
     pub prev: Option<Token<'source_lifetime>>,
     pub had_error: bool,
     pub panic_mode: bool,
+    pub errors: Vec<CompileError>,
     pub scanner: Scanner<'source_lifetime>,
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, IntoPrimitive, UnsafeFromPrimitive, Copy, Clone)]
 #[repr(u8)]
 pub enum Precedence {
@@ -36,12 +39,13 @@ impl<'source_lifetime> Parser<'source_lifetime> {
             prev: None,
             had_error: false,
             panic_mode: false,
+            errors: vec![],
             scanner: Scanner::new(source),
         }
     }
 
     pub fn advance(&mut self) {
-        self.prev = std::mem::replace(&mut self.current, None);
+        self.prev = self.current.take();
 
         loop {
             let token = self.scanner.scan_token();
@@ -52,8 +56,8 @@ impl<'source_lifetime> Parser<'source_lifetime> {
                     break;
                 }
                 Err(msg) => {
-                    self.error_at_current(msg);
-                    self.had_error = true;
+                    let token = self.scanner.error_token();
+                    self.report(&token, token.lexeme, msg);
                 }
             }
         }
@@ -68,28 +72,69 @@ impl<'source_lifetime> Parser<'source_lifetime> {
     }
 
     fn error_at(&mut self, token: &str, msg: &'static str) {
-        let t = if token.eq("current") && self.current.is_some() {
-            self.current.as_ref().unwrap()
-        } else {
-            self.prev.as_ref().unwrap()
+        let mut t = match &self.current {
+            Some(current) if token.eq("current") => *current,
+            _ => *self.prev.as_ref().unwrap(),
         };
+        if t.token_type != Eof {
+            self.report(&t, t.lexeme, msg);
+            return;
+        }
+        // Point just past the last code rather than at trailing blank lines.
+        let source = self.scanner.source();
+        t.offset = source.trim_end().len();
+        t.line = source[..t.offset].matches('\n').count() as i32 + 1;
+        t.column = line_at(source, t.offset).0;
+        self.report(&t, "", msg);
+    }
+
+    fn report(&mut self, token: &Token, lexeme: &str, msg: &'static str) {
         if self.panic_mode {
             return;
         }
         self.panic_mode = true;
-        eprint!("[line {}] Error", t.line);
-        if t.token_type == Eof {
-            eprint!(" at end");
-        } else {
-            eprint!(" at {}", t.lexeme);
+        let (_, source_line) = line_at(self.scanner.source(), token.offset);
+        self.errors.push(CompileError {
+            line: token.line,
+            column: token.column,
+            lexeme: lexeme.to_string(),
+            message: msg.to_string(),
+            span: token.span(),
+            source_line: source_line.to_string(),
+        });
+        self.had_error = true;
+    }
+
+    // Leaves panic mode by skipping to the next likely statement boundary, so
+    // errors after it are reported independently of the one that got us here.
+    pub fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while !self.check(Eof) {
+            if matches!(&self.prev, Some(t) if t.token_type == Semicolon) {
+                return;
+            }
+            match self.current.as_ref().unwrap().token_type {
+                Class | Fun | Var | For | If | While | Print | Return => return,
+                _ => self.advance(),
+            }
         }
+    }
 
-        eprintln!(": {}", msg);
-        self.had_error = true;
+    pub fn check(&self, token_type: TokenType) -> bool {
+        self.current.as_ref().unwrap().token_type == token_type
+    }
+
+    pub fn match_token(&mut self, token_type: TokenType) -> bool {
+        if self.check(token_type) {
+            self.advance();
+            true
+        } else {
+            false
+        }
     }
 
     pub fn consume(&mut self, token_type: TokenType, msg: &'static str) {
-        if self.current.as_ref().unwrap().token_type == token_type {
+        if self.check(token_type) {
             self.advance()
         } else {
             self.error_at_current(msg);
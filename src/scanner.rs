@@ -1,5 +1,6 @@
 use crate::common::TokenType;
 use crate::common::TokenType::*;
+use crate::error::Span;
 
 #[derive(Debug)]
 pub struct Scanner<'source_lifetime> {
@@ -7,13 +8,31 @@ pub struct Scanner<'source_lifetime> {
     current: usize,
     source: &'source_lifetime str,
     line: i32,
+    // A byte offset on the current line and its 1-based column, so columns
+    // are counted in characters without rescanning the line for each token.
+    column_mark: (usize, usize),
+    // Line and 1-based column where the token being scanned begins.
+    start_line: i32,
+    start_column: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct Token<'source_lifetime> {
     pub token_type: TokenType,
     pub lexeme: &'source_lifetime str,
     pub line: i32,
+    pub column: usize,
+    // Byte offset of the lexeme in the source.
+    pub offset: usize,
+}
+
+impl<'source_lifetime> Token<'source_lifetime> {
+    pub fn span(&self) -> Span {
+        Span {
+            offset: self.offset,
+            len: self.lexeme.len(),
+        }
+    }
 }
 
 impl<'source_lifetime> Scanner<'source_lifetime> {
@@ -22,6 +41,9 @@ impl<'source_lifetime> Scanner<'source_lifetime> {
             start: 0,
             current: 0,
             line: 1,
+            column_mark: (0, 1),
+            start_line: 1,
+            start_column: 1,
             source,
         }
     }
@@ -34,8 +56,8 @@ impl<'source_lifetime> Scanner<'source_lifetime> {
     // }
 
     pub fn scan_token(&mut self) -> Result<Token<'source_lifetime>, &'static str> {
-        self.skip_whitespace();
-        self.start = self.current;
+        self.skip_whitespace()?;
+        self.mark_start();
         if self.is_at_end() {
             Ok(self.make_token(Eof))
         } else {
@@ -82,10 +104,18 @@ impl<'source_lifetime> Scanner<'source_lifetime> {
                 }
                 '?' => Ok(self.make_token(Question)),
                 ':' => Ok(self.make_token(Colon)),
+                '[' => Ok(self.make_token(LeftBracket)),
+                ']' => Ok(self.make_token(RightBracket)),
                 '"' => self.string(),
                 d if d.is_ascii_digit() => Ok(self.number()),
                 a if a.is_ascii_alphabetic() => Ok(self.identifier()),
-                _ => Err("Unexpected character."),
+                _ => {
+                    // Skip the rest of a multi-byte character.
+                    while !self.source.is_char_boundary(self.current) {
+                        self.current += 1;
+                    }
+                    Err("Unexpected character.")
+                }
             }
         }
     }
@@ -113,10 +143,34 @@ impl<'source_lifetime> Scanner<'source_lifetime> {
         Token {
             token_type,
             lexeme: &self.source[{ self.start }..{ self.current }],
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            offset: self.start,
         }
     }
 
+    pub fn source(&self) -> &'source_lifetime str {
+        self.source
+    }
+
+    // Whatever `scan_token` just rejected, as a token of no particular type.
+    pub fn error_token(&self) -> Token<'source_lifetime> {
+        self.make_token(Eof)
+    }
+
+    fn mark_start(&mut self) {
+        self.start = self.current;
+        self.start_line = self.line;
+        let (offset, column) = self.column_mark;
+        self.start_column = column + self.source[offset..self.start].chars().count();
+        self.column_mark = (self.start, self.start_column);
+    }
+
+    fn newline(&mut self) {
+        self.line += 1;
+        self.column_mark = (self.current + 1, 1);
+    }
+
     #[inline(always)]
     pub fn advance(&mut self) -> char {
         self.current += 1;
@@ -125,19 +179,23 @@ impl<'source_lifetime> Scanner<'source_lifetime> {
 
     #[inline(always)]
     pub fn peek(&self) -> char {
-        self.source.as_bytes()[self.current] as char
+        self.source
+            .as_bytes()
+            .get(self.current)
+            .map_or('\0', |&b| b as char)
     }
 
     #[inline(always)]
     pub fn peek_next(&self) -> char {
-        self.source.as_bytes()[self.current + 1] as char
+        self.source
+            .as_bytes()
+            .get(self.current + 1)
+            .map_or('\0', |&b| b as char)
     }
 
     #[inline(always)]
     pub fn match_char(&mut self, expected: char) -> bool {
-        if self.is_at_end() {
-            false
-        } else if self.peek() != expected {
+        if self.is_at_end() || self.peek() != expected {
             false
         } else {
             self.current += 1;
@@ -145,10 +203,10 @@ impl<'source_lifetime> Scanner<'source_lifetime> {
         }
     }
 
-    fn skip_whitespace(&mut self) -> () {
+    fn skip_whitespace(&mut self) -> Result<(), &'static str> {
         loop {
             if self.is_at_end() {
-                break;
+                return Ok(());
             }
             match self.peek() {
                 ' ' => {
@@ -161,39 +219,51 @@ impl<'source_lifetime> Scanner<'source_lifetime> {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
+                    self.newline();
                     self.advance();
                 }
                 '/' => {
-                    self.skip_comment();
-                    return;
+                    if !self.skip_comment()? {
+                        return Ok(());
+                    }
                 }
-                _ => return (),
+                _ => return Ok(()),
             }
         }
     }
 
-    fn skip_comment(&mut self) {
+    // Returns false if the slash doesn't start a comment.
+    fn skip_comment(&mut self) -> Result<bool, &'static str> {
         if self.peek_next() == '/' {
             while self.peek() != '\n' && !self.is_at_end() {
                 self.advance();
             }
+            Ok(true)
         } else if self.peek_next() == '*' {
-            while self.peek() != '*' && self.peek_next() != '/' && !self.is_at_end() {
+            // Errors point at the opening "/*". Step past it so "/*/" doesn't
+            // close itself.
+            self.mark_start();
+            self.current += 2;
+            while !(self.peek() == '*' && self.peek_next() == '/') {
+                if self.is_at_end() {
+                    return Err("Unterminated comment.");
+                }
                 if self.peek() == '\n' {
-                    self.line += 1;
+                    self.newline();
                 }
                 self.advance();
             }
+            self.current += 2;
+            Ok(true)
         } else {
-            return ();
+            Ok(false)
         }
     }
 
     fn string(&mut self) -> Result<Token<'source_lifetime>, &'static str> {
         while !self.is_at_end() && self.peek() != '"' {
             if self.peek() == '\n' {
-                self.line += 1;
+                self.newline();
             }
             self.advance();
         }
@@ -0,0 +1,307 @@
+use crate::gc::Gc;
+use crate::obj::ObjString;
+use crate::value::Value;
+
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+pub trait TableKey: Copy + PartialEq {
+    fn hash_key(&self) -> u32;
+}
+
+impl TableKey for Gc<ObjString> {
+    fn hash_key(&self) -> u32 {
+        self.hash
+    }
+}
+
+// Only nil, booleans, numbers and strings are hashable; the VM rejects any
+// other key before it reaches a table.
+impl TableKey for Value {
+    fn hash_key(&self) -> u32 {
+        if self.is_number() {
+            // 0 and -0 are equal, so they must hash alike.
+            let n = self.as_number();
+            let bits = if n == 0.0 { 0 } else { n.to_bits() };
+            (bits ^ (bits >> 32)) as u32
+        } else if self.is_bool() {
+            if self.as_bool() {
+                3
+            } else {
+                2
+            }
+        } else if self.is_nil() {
+            1
+        } else {
+            self.downcast::<ObjString>()
+                .expect("Map keys are hashable.")
+                .hash
+        }
+    }
+}
+
+// An empty bucket has no key and a nil value, a tombstone has no key and a
+// true value so probing continues past deleted entries.
+#[derive(Copy, Clone)]
+struct Entry<K> {
+    key: Option<K>,
+    value: Value,
+}
+
+impl<K> Entry<K> {
+    fn is_tombstone(&self) -> bool {
+        self.key.is_none() && self.value == Value::bool(true)
+    }
+}
+
+// Open-addressing hash table. Strings are interned, so string keys compare by
+// pointer.
+pub struct HashTable<K> {
+    // Live entries plus tombstones.
+    count: usize,
+    entries: Vec<Entry<K>>,
+}
+
+pub type Table = HashTable<Gc<ObjString>>;
+
+// Keyed by any hashable value, for maps.
+pub type ValueTable = HashTable<Value>;
+
+impl<K: TableKey> HashTable<K> {
+    pub fn new() -> Self {
+        HashTable {
+            count: 0,
+            entries: vec![],
+        }
+    }
+
+    // Bytes held by the entry array, for heap accounting.
+    pub fn heap_size(&self) -> usize {
+        self.entries.capacity() * size_of::<Entry<K>>()
+    }
+
+    pub fn get(&self, key: K) -> Option<Value> {
+        if self.count == 0 {
+            return None;
+        }
+        let entry = &self.entries[self.find_entry(key)];
+        entry.key.map(|_| entry.value)
+    }
+
+    // Returns true if `key` was not in the table before.
+    pub fn set(&mut self, key: K, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.entries.len() as f64 * TABLE_MAX_LOAD {
+            self.adjust_capacity((self.entries.len() * 2).max(8));
+        }
+        let index = self.find_entry(key);
+        let entry = &mut self.entries[index];
+        let is_new = entry.key.is_none();
+        if is_new && !entry.is_tombstone() {
+            self.count += 1;
+        }
+        entry.key = Some(key);
+        entry.value = value;
+        is_new
+    }
+
+    pub fn delete(&mut self, key: K) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+        let index = self.find_entry(key);
+        let entry = &mut self.entries[index];
+        if entry.key.is_none() {
+            return false;
+        }
+        entry.key = None;
+        entry.value = Value::bool(true);
+        true
+    }
+
+    pub fn add_all(&mut self, from: &HashTable<K>) {
+        for (key, value) in from.iter() {
+            self.set(key, value);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (K, Value)> + '_ {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.key.map(|key| (key, entry.value)))
+    }
+
+    fn find_entry(&self, key: K) -> usize {
+        let capacity = self.entries.len();
+        let mut index = key.hash_key() as usize & (capacity - 1);
+        let mut tombstone = None;
+        loop {
+            let entry = &self.entries[index];
+            match entry.key {
+                Some(k) if k == key => return index,
+                Some(_) => {}
+                None if entry.is_tombstone() => {
+                    tombstone.get_or_insert(index);
+                }
+                None => return tombstone.unwrap_or(index),
+            }
+            index = (index + 1) & (capacity - 1);
+        }
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let empty = Entry {
+            key: None,
+            value: Value::nil(),
+        };
+        let old = std::mem::replace(&mut self.entries, vec![empty; capacity]);
+        // Tombstones are dropped while rehashing.
+        self.count = 0;
+        for entry in old.iter() {
+            if let Some(key) = entry.key {
+                let index = self.find_entry(key);
+                self.entries[index] = *entry;
+                self.count += 1;
+            }
+        }
+    }
+}
+
+impl Table {
+    // Looks a string up by content rather than identity, for interning.
+    pub fn find_string(&self, s: &str, hash: u32) -> Option<Gc<ObjString>> {
+        if self.count == 0 {
+            return None;
+        }
+        let mut index = hash as usize & (self.entries.len() - 1);
+        loop {
+            let entry = &self.entries[index];
+            match entry.key {
+                None if !entry.is_tombstone() => return None,
+                Some(key) if key.hash == hash && key.as_str() == s => return Some(key),
+                _ => {}
+            }
+            index = (index + 1) & (self.entries.len() - 1);
+        }
+    }
+
+    // Deletes every entry whose key was not marked, so the table holds its
+    // keys weakly.
+    pub fn remove_white(&mut self) {
+        for entry in self.entries.iter_mut() {
+            if let Some(key) = entry.key {
+                if !key.as_obj().is_marked() {
+                    entry.key = None;
+                    entry.value = Value::bool(true);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::obj;
+    use crate::vm::VM;
+
+    // Interned strings stay rooted on the VM stack for the rest of the test.
+    fn strings(vm: &mut VM, names: &[String]) -> Vec<Gc<ObjString>> {
+        names
+            .iter()
+            .map(|name| {
+                let string = obj::copy_string(vm, name);
+                vm.push(Value::obj(string));
+                string
+            })
+            .collect()
+    }
+
+    // Keys whose hashes share a bucket in an 8-entry table.
+    fn colliding(count: usize) -> Vec<String> {
+        let bucket = |s: &String| obj::hash_string(s.as_bytes()) & 7;
+        let first = "k0".to_string();
+        (0..)
+            .map(|i| format!("k{}", i))
+            .filter(|s| bucket(s) == bucket(&first))
+            .take(count)
+            .collect()
+    }
+
+    fn number(value: Option<Value>) -> Option<f64> {
+        value.filter(|v| v.is_number()).map(Value::as_number)
+    }
+
+    #[test]
+    fn probes_past_tombstones() {
+        let mut vm = VM::new();
+        let keys = strings(&mut vm, &colliding(3));
+        let mut table = Table::new();
+        for (i, &key) in keys.iter().enumerate() {
+            assert!(table.set(key, Value::number(i as f64)));
+        }
+        assert!(table.delete(keys[0]));
+        assert!(!table.delete(keys[0]));
+        assert_eq!(number(table.get(keys[0])), None);
+        assert_eq!(number(table.get(keys[2])), Some(2.0));
+
+        // Reinserting reuses the tombstone rather than counting a new entry.
+        assert!(table.set(keys[0], Value::number(10.0)));
+        assert_eq!(table.count, 3);
+        assert_eq!(number(table.get(keys[0])), Some(10.0));
+        assert_eq!(table.iter().count(), 3);
+    }
+
+    #[test]
+    fn grows_before_exceeding_max_load() {
+        let mut vm = VM::new();
+        let names: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+        let keys = strings(&mut vm, &names);
+        let mut table = Table::new();
+        for (i, &key) in keys.iter().enumerate() {
+            assert!(table.set(key, Value::number(i as f64)));
+            assert!(table.count as f64 <= table.entries.len() as f64 * TABLE_MAX_LOAD);
+        }
+        assert_eq!(table.entries.len(), 256);
+        for (i, &key) in keys.iter().enumerate() {
+            assert_eq!(number(table.get(key)), Some(i as f64));
+        }
+        assert!(!table.set(keys[5], Value::number(-1.0)));
+        assert_eq!(number(table.get(keys[5])), Some(-1.0));
+    }
+
+    #[test]
+    fn growing_drops_tombstones() {
+        let mut vm = VM::new();
+        let names: Vec<String> = (0..7).map(|i| format!("key{}", i)).collect();
+        let keys = strings(&mut vm, &names);
+        let mut table = Table::new();
+        for &key in keys[..6].iter() {
+            table.set(key, Value::nil());
+        }
+        for &key in keys[..6].iter() {
+            assert!(table.delete(key));
+        }
+        // Tombstones still count towards the load, so this insert grows.
+        assert_eq!(table.count, 6);
+        table.set(keys[6], Value::nil());
+        assert_eq!(table.entries.len(), 16);
+        assert_eq!(table.count, 1);
+        assert_eq!(table.iter().count(), 1);
+    }
+
+    #[test]
+    fn find_string_compares_contents() {
+        let mut vm = VM::new();
+        let keys = strings(&mut vm, &colliding(2));
+        let mut table = Table::new();
+        table.set(keys[0], Value::nil());
+        table.set(keys[1], Value::nil());
+        table.delete(keys[0]);
+        let name = keys[1].as_str().to_string();
+        let hash = obj::hash_string(name.as_bytes());
+        assert!(table.find_string(&name, hash) == Some(keys[1]));
+        let gone = keys[0].as_str().to_string();
+        assert!(table
+            .find_string(&gone, obj::hash_string(gone.as_bytes()))
+            .is_none());
+    }
+}
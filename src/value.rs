@@ -1,17 +1,393 @@
-use crate::obj::{print_obj, Object};
+use crate::error::RuntimeError;
+use crate::gc::{Gc, ObjRef, Object};
+use crate::obj::print_obj;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+// Code outside this module goes through the constructors, predicates and
+// accessors below, so either representation can be compiled in. The `as_*`
+// accessors expect the matching `is_*` check to have been done first.
+
+#[cfg(not(feature = "nan-boxing"))]
 #[derive(Debug, Copy, Clone)]
 pub enum Value {
     Bool(bool),
     Number(f64),
-    Obj(*mut dyn Object),
+    Obj(ObjRef),
     Empty,
 }
 
-pub fn print_value(value: Value) -> () {
-    match value {
-        Value::Number(v) => print!("{}", v),
-        Value::Bool(v) => print!("{}", v),
-        Value::Empty => print!(""),
-        Value::Obj(o) => print_obj(o),
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+    pub const fn nil() -> Self {
+        Value::Empty
+    }
+
+    pub const fn bool(b: bool) -> Self {
+        Value::Bool(b)
+    }
+
+    pub const fn number(n: f64) -> Self {
+        Value::Number(n)
+    }
+
+    pub fn obj(o: impl Into<ObjRef>) -> Self {
+        Value::Obj(o.into())
+    }
+
+    pub fn is_nil(self) -> bool {
+        matches!(self, Value::Empty)
+    }
+
+    pub fn is_bool(self) -> bool {
+        matches!(self, Value::Bool(_))
+    }
+
+    pub fn is_number(self) -> bool {
+        matches!(self, Value::Number(_))
+    }
+
+    pub fn is_obj(self) -> bool {
+        matches!(self, Value::Obj(_))
+    }
+
+    pub fn as_bool(self) -> bool {
+        match self {
+            Value::Bool(b) => b,
+            _ => unreachable!("Value is not a bool."),
+        }
+    }
+
+    pub fn as_number(self) -> f64 {
+        match self {
+            Value::Number(n) => n,
+            _ => unreachable!("Value is not a number."),
+        }
+    }
+
+    pub fn as_obj(self) -> ObjRef {
+        match self {
+            Value::Obj(o) => o,
+            _ => unreachable!("Value is not an object."),
+        }
+    }
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Bool(b1), Value::Bool(b2)) => b1 == b2,
+            (Value::Number(n1), Value::Number(n2)) => n1 == n2,
+            (Value::Empty, Value::Empty) => true,
+            // Strings are interned, so identity is equality for every object.
+            (Value::Obj(o1), Value::Obj(o2)) => o1 == o2,
+            _ => false,
+        }
+    }
+}
+
+// Numbers are stored as their own bits. Every other value is a quiet NaN:
+// nil and the booleans are tagged in the low bits, and objects set the sign bit
+// with the pointer in the low 48 bits.
+#[cfg(feature = "nan-boxing")]
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const TAG_NIL: u64 = 1;
+#[cfg(feature = "nan-boxing")]
+const TAG_FALSE: u64 = 2;
+#[cfg(feature = "nan-boxing")]
+const TAG_TRUE: u64 = 3;
+
+#[cfg(feature = "nan-boxing")]
+#[derive(Debug, Copy, Clone)]
+pub struct Value(u64);
+
+#[cfg(feature = "nan-boxing")]
+impl Value {
+    pub const fn nil() -> Self {
+        Value(QNAN | TAG_NIL)
+    }
+
+    pub const fn bool(b: bool) -> Self {
+        if b {
+            Value(QNAN | TAG_TRUE)
+        } else {
+            Value(QNAN | TAG_FALSE)
+        }
+    }
+
+    pub const fn number(n: f64) -> Self {
+        Value(n.to_bits())
+    }
+
+    pub fn obj(o: impl Into<ObjRef>) -> Self {
+        Value(SIGN_BIT | QNAN | o.into().to_bits())
+    }
+
+    pub fn is_nil(self) -> bool {
+        self.0 == (QNAN | TAG_NIL)
+    }
+
+    pub fn is_bool(self) -> bool {
+        (self.0 | 1) == (QNAN | TAG_TRUE)
+    }
+
+    pub fn is_number(self) -> bool {
+        (self.0 & QNAN) != QNAN
+    }
+
+    pub fn is_obj(self) -> bool {
+        (self.0 & (SIGN_BIT | QNAN)) == (SIGN_BIT | QNAN)
+    }
+
+    pub fn as_bool(self) -> bool {
+        self.0 == (QNAN | TAG_TRUE)
+    }
+
+    pub fn as_number(self) -> f64 {
+        f64::from_bits(self.0)
+    }
+
+    pub fn as_obj(self) -> ObjRef {
+        debug_assert!(self.is_obj());
+        // Object values are only built by `Value::obj` from a live object.
+        unsafe { ObjRef::from_bits(self.0 & !(SIGN_BIT | QNAN)) }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        // Compare numbers as floats so NaN != NaN like the enum version.
+        if self.is_number() && other.is_number() {
+            self.as_number() == other.as_number()
+        } else {
+            self.0 == other.0
+        }
+    }
+}
+
+impl Value {
+    pub fn is_obj_type<T: Object>(self) -> bool {
+        self.is_obj() && self.as_obj().is::<T>()
+    }
+
+    pub fn downcast<T: Object>(self) -> Option<Gc<T>> {
+        if self.is_obj() {
+            self.as_obj().downcast()
+        } else {
+            None
+        }
+    }
+
+    pub fn is_falsey(self) -> bool {
+        self.is_nil() || (self.is_bool() && !self.as_bool())
+    }
+}
+
+// Values passed between the VM and embedders or native functions. Nil,
+// booleans, numbers and strings are copied out, any other object is handed
+// out as a `Handle` that keeps it alive.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Object(Handle),
+}
+
+// A VM object held by the host. The VM that made it marks it as a root until
+// the last clone is dropped, and refuses handles made by another VM.
+#[derive(Clone, PartialEq)]
+pub struct Handle(pub(crate) Rc<Root>);
+
+#[derive(PartialEq)]
+pub(crate) struct Root {
+    pub(crate) vm: usize,
+    pub(crate) value: Value,
+}
+
+// The object may already be freed if its VM was dropped, so only the owning
+// VM is named.
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Handle(vm {})", self.0.vm)
+    }
+}
+
+impl From<f64> for LoxValue {
+    fn from(n: f64) -> Self {
+        LoxValue::Number(n)
+    }
+}
+
+impl From<bool> for LoxValue {
+    fn from(b: bool) -> Self {
+        LoxValue::Bool(b)
+    }
+}
+
+impl From<()> for LoxValue {
+    fn from(_: ()) -> Self {
+        LoxValue::Nil
+    }
+}
+
+impl From<String> for LoxValue {
+    fn from(s: String) -> Self {
+        LoxValue::String(s)
+    }
+}
+
+impl From<&str> for LoxValue {
+    fn from(s: &str) -> Self {
+        LoxValue::String(s.to_string())
+    }
+}
+
+impl From<Handle> for LoxValue {
+    fn from(handle: Handle) -> Self {
+        LoxValue::Object(handle)
+    }
+}
+
+impl TryFrom<LoxValue> for f64 {
+    type Error = RuntimeError;
+
+    fn try_from(value: LoxValue) -> Result<Self, Self::Error> {
+        match value {
+            LoxValue::Number(n) => Ok(n),
+            _ => Err(RuntimeError::new("Expected a number.")),
+        }
+    }
+}
+
+impl TryFrom<LoxValue> for bool {
+    type Error = RuntimeError;
+
+    fn try_from(value: LoxValue) -> Result<Self, Self::Error> {
+        match value {
+            LoxValue::Bool(b) => Ok(b),
+            _ => Err(RuntimeError::new("Expected a boolean.")),
+        }
+    }
+}
+
+impl TryFrom<LoxValue> for () {
+    type Error = RuntimeError;
+
+    fn try_from(value: LoxValue) -> Result<Self, Self::Error> {
+        match value {
+            LoxValue::Nil => Ok(()),
+            _ => Err(RuntimeError::new("Expected nil.")),
+        }
+    }
+}
+
+impl TryFrom<LoxValue> for String {
+    type Error = RuntimeError;
+
+    fn try_from(value: LoxValue) -> Result<Self, Self::Error> {
+        match value {
+            LoxValue::String(s) => Ok(s),
+            _ => Err(RuntimeError::new("Expected a string.")),
+        }
+    }
+}
+
+impl TryFrom<LoxValue> for Handle {
+    type Error = RuntimeError;
+
+    fn try_from(value: LoxValue) -> Result<Self, Self::Error> {
+        match value {
+            LoxValue::Object(handle) => Ok(handle),
+            _ => Err(RuntimeError::new("Expected an object.")),
+        }
+    }
+}
+
+pub fn print_value(value: Value) {
+    if value.is_number() {
+        print!("{}", value.as_number())
+    } else if value.is_bool() {
+        print!("{}", value.as_bool())
+    } else if value.is_nil() {
+        print!("nil")
+    } else {
+        print_obj(value.as_obj())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::obj;
+    use crate::obj::ObjString;
+    use crate::vm::VM;
+
+    #[test]
+    fn numbers_round_trip() {
+        for &n in [
+            0.0,
+            -0.0,
+            1.5,
+            -2.0,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ]
+        .iter()
+        {
+            let value = Value::number(n);
+            assert!(value.is_number() && !value.is_nil() && !value.is_bool() && !value.is_obj());
+            assert_eq!(value.as_number().to_bits(), n.to_bits());
+        }
+        assert_eq!(Value::number(0.0), Value::number(-0.0));
+    }
+
+    #[test]
+    fn nans_stay_numbers() {
+        // Arithmetic produces both signs of NaN, neither may decode as a tag.
+        let negative_nan = f64::from_bits(0xfff8_0000_0000_0000);
+        for &n in [f64::NAN, negative_nan, f64::INFINITY - f64::INFINITY].iter() {
+            let value = Value::number(n);
+            assert!(value.is_number() && !value.is_obj());
+            assert!(value.as_number().is_nan());
+            assert_ne!(value, value);
+        }
+    }
+
+    #[test]
+    fn nil_and_booleans() {
+        assert!(Value::nil().is_nil() && !Value::nil().is_bool() && !Value::nil().is_number());
+        for &b in [true, false].iter() {
+            let value = Value::bool(b);
+            assert!(value.is_bool() && !value.is_nil() && !value.is_number() && !value.is_obj());
+            assert_eq!(value.as_bool(), b);
+        }
+        assert_ne!(Value::bool(true), Value::bool(false));
+        assert_ne!(Value::bool(false), Value::nil());
+        assert!(Value::nil().is_falsey() && Value::bool(false).is_falsey());
+        assert!(!Value::bool(true).is_falsey() && !Value::number(0.0).is_falsey());
+    }
+
+    #[test]
+    fn objects_round_trip() {
+        let mut vm = VM::new();
+        let s = obj::copy_string(&mut vm, "boxed");
+        let value = Value::obj(s);
+        vm.push(value);
+        assert!(value.is_obj() && !value.is_number() && !value.is_nil() && !value.is_bool());
+        assert!(value.is_obj_type::<ObjString>());
+        assert!(value.as_obj() == s.as_obj());
+        assert_eq!(value.downcast::<ObjString>().unwrap().as_str(), "boxed");
+        assert_eq!(value, Value::obj(obj::copy_string(&mut vm, "boxed")));
+        assert!(Value::number(1.0).downcast::<ObjString>().is_none());
     }
 }
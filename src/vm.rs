@@ -1,126 +1,503 @@
 use crate::chunk::Chunk;
 use crate::common::OpCode;
 use crate::compiler::Compiler;
-use crate::gc::GC;
-use crate::obj::{self, ObjString, ObjType, Object};
-use crate::value::{print_value, Value};
+use crate::error::{
+    line_at, CompileError, ErrorReporter, RuntimeError, StackFrame, StderrReporter,
+};
+use crate::gc::{Gc, HeapStats, Object, GC};
+use crate::native;
+use crate::obj::{
+    self, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjList, ObjMap, ObjNative, ObjString,
+    ObjUpvalue,
+};
+use crate::obj::{NativeFn, ObjFunction};
+use crate::table::Table;
+use crate::value::{print_value, Handle, LoxValue, Root, Value};
 use crate::vm::InterpretResult::{InterpretCompileError, InterpretOk, InterpretRuntimeError};
-use std::alloc::Layout;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub struct VM {
-    chunk: Chunk,
+const FRAMES_MAX: usize = 64;
+// Calls fail with "Stack overflow." once the stack is this deep. Locals and
+// temporaries of the frames below can still run past it, so `push` grows the
+// stack rather than relying on it as a hard bound.
+const STACK_MAX: usize = FRAMES_MAX * 256;
+
+// Tells VMs apart so a handle can't be used with a VM that didn't make it.
+static NEXT_VM_ID: AtomicUsize = AtomicUsize::new(0);
+
+struct CallFrame {
+    closure: Gc<ObjClosure>,
     ip: usize,
+    // Index of the frame's first slot in VM::stack.
+    slots: usize,
+}
+
+pub struct VM {
+    id: usize,
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
     top_stack: usize,
+    globals: Table,
+    // Every live string, so equal strings share one object.
+    strings: Table,
+    init_string: Option<Gc<ObjString>>,
+    open_upvalues: Option<Gc<ObjUpvalue>>,
+    gc: GC,
+    // Names and constants the compiler made that no heap object refers to
+    // until their function is allocated.
+    compiler_roots: Vec<Value>,
+    // The runtime error being unwound, until `run` hands it back.
+    error: Option<RuntimeError>,
+    // Objects handed out to the host, dead once their handles are dropped.
+    handles: Vec<Weak<Root>>,
+    reporter: Box<dyn ErrorReporter>,
 }
 
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum InterpretResult {
     InterpretOk,
-    InterpretCompileError,
-    InterpretRuntimeError,
+    InterpretCompileError(Vec<CompileError>),
+    InterpretRuntimeError(RuntimeError),
 }
 
 macro_rules! binary_op {
-    ($self:ident, $variant:ident, $op: tt) => {
+    ($self:ident, $ctor:ident, $op: tt) => {
     {
         let b = $self.pop();
         let a = $self.pop();
-        match (a, b) {
-            (Value::Number(av), Value::Number(bv)) => {
-                $self.push(Value::$variant(av $op bv));
-            }
-            _ => {
-                eprintln!("Operands must be numbers.");
-                $self.runtime_error();
-                return InterpretRuntimeError
-            }
+        if a.is_number() && b.is_number() {
+            $self.push(Value::$ctor(a.as_number() $op b.as_number()));
+        } else {
+            $self.runtime_error("Operands must be numbers.");
+            return InterpretRuntimeError($self.take_error())
         }
     }
     }
 }
 
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
+}
+
 impl VM {
     pub fn new() -> Self {
-        VM {
-            chunk: Chunk::new(),
-            ip: 0,
-            stack: vec![Value::Empty; 256],
+        let mut vm = VM {
+            id: NEXT_VM_ID.fetch_add(1, Ordering::Relaxed),
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: vec![Value::nil(); STACK_MAX],
             top_stack: 0,
+            globals: Table::new(),
+            strings: Table::new(),
+            init_string: None,
+            open_upvalues: None,
+            gc: GC::new(),
+            compiler_roots: vec![],
+            error: None,
+            handles: vec![],
+            reporter: Box::new(StderrReporter),
+        };
+        vm.init_string = Some(obj::copy_string(&mut vm, "init"));
+        vm.define_native("clock", 0, native::clock);
+        vm
+    }
+
+    // Binds a host function to a global so scripts can call it.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let name = obj::copy_string(self, name);
+        self.push(Value::obj(name));
+        let native = obj::new_native(self, name, arity, function);
+        self.push(Value::obj(native));
+        self.globals.set(name, Value::obj(native));
+        self.pop();
+        self.pop();
+    }
+
+    pub fn set_global(
+        &mut self,
+        name: &str,
+        value: impl Into<LoxValue>,
+    ) -> Result<(), RuntimeError> {
+        let value = self.import(&value.into())?;
+        self.push(value);
+        let name = obj::copy_string(self, name);
+        self.globals.set(name, value);
+        self.pop();
+        Ok(())
+    }
+
+    pub fn get_global(&mut self, name: &str) -> Result<LoxValue, RuntimeError> {
+        let value = self.global(name)?;
+        Ok(self.export(value))
+    }
+
+    // Replaces the reporter `interpret` passes errors to before returning them.
+    pub fn set_reporter(&mut self, reporter: Box<dyn ErrorReporter>) {
+        self.reporter = reporter;
+    }
+
+    // Calls the function, class, bound method or native stored in the global
+    // `name` with `args`. Errors are returned to the caller rather than
+    // reported.
+    pub fn call_function(
+        &mut self,
+        name: &str,
+        args: &[LoxValue],
+    ) -> Result<LoxValue, RuntimeError> {
+        let callee = self.global(name)?;
+        self.call_with(callee, args)
+    }
+
+    // Calls a function, class, bound method or native the host holds a handle
+    // to.
+    pub fn call(&mut self, callee: &LoxValue, args: &[LoxValue]) -> Result<LoxValue, RuntimeError> {
+        let callee = self.import(callee)?;
+        self.call_with(callee, args)
+    }
+
+    pub fn new_list(&mut self, items: &[LoxValue]) -> Result<LoxValue, RuntimeError> {
+        let stack_base = self.top_stack;
+        // The items stay on the stack until the list owns them.
+        for item in items {
+            match self.import(item) {
+                Ok(item) => self.push(item),
+                Err(error) => {
+                    self.top_stack = stack_base;
+                    return Err(error);
+                }
+            }
+        }
+        let items = self.stack[stack_base..self.top_stack].to_vec();
+        let list = obj::new_list(self, items);
+        self.top_stack = stack_base;
+        Ok(self.export(Value::obj(list)))
+    }
+
+    pub fn list_items(&mut self, list: &LoxValue) -> Result<Vec<LoxValue>, RuntimeError> {
+        let list = match self.import(list)?.downcast::<ObjList>() {
+            Some(list) => list,
+            None => return Err(RuntimeError::new("Expected a list.")),
+        };
+        let items = list.items.borrow().clone();
+        Ok(items.into_iter().map(|item| self.export(item)).collect())
+    }
+
+    pub fn new_map(&mut self, entries: &[(LoxValue, LoxValue)]) -> Result<LoxValue, RuntimeError> {
+        let stack_base = self.top_stack;
+        // The entries stay on the stack until the map owns them.
+        for (key, value) in entries {
+            let entry = self.import(key).and_then(|key| {
+                if let Some(message) = VM::map_key_error(key) {
+                    return Err(RuntimeError::new(message));
+                }
+                self.push(key);
+                self.import(value)
+            });
+            match entry {
+                Ok(value) => self.push(value),
+                Err(error) => {
+                    self.top_stack = stack_base;
+                    return Err(error);
+                }
+            }
+        }
+        let map = obj::new_map(self);
+        let entries = &self.stack[stack_base..self.top_stack];
+        self.gc.resize(map, |map| {
+            let mut table = map.table.borrow_mut();
+            for entry in entries.chunks(2) {
+                table.set(entry[0], entry[1]);
+            }
+        });
+        self.top_stack = stack_base;
+        Ok(self.export(Value::obj(map)))
+    }
+
+    pub fn map_entries(
+        &mut self,
+        map: &LoxValue,
+    ) -> Result<Vec<(LoxValue, LoxValue)>, RuntimeError> {
+        let map = match self.import(map)?.downcast::<ObjMap>() {
+            Some(map) => map,
+            None => return Err(RuntimeError::new("Expected a map.")),
+        };
+        let entries: Vec<_> = map.table.borrow().iter().collect();
+        Ok(entries
+            .into_iter()
+            .map(|(key, value)| (self.export(key), self.export(value)))
+            .collect())
+    }
+
+    fn global(&self, name: &str) -> Result<Value, RuntimeError> {
+        // A name that was never interned can't be a global.
+        self.find_string(name, obj::hash_string(name.as_bytes()))
+            .and_then(|key| self.globals.get(key))
+            .ok_or_else(|| RuntimeError::new(format!("Undefined variable '{}'.", name)))
+    }
+
+    fn call_with(&mut self, callee: Value, args: &[LoxValue]) -> Result<LoxValue, RuntimeError> {
+        let base = self.frames.len();
+        let stack_base = self.top_stack;
+        self.push(callee);
+        for arg in args {
+            match self.import(arg) {
+                Ok(arg) => self.push(arg),
+                Err(error) => {
+                    self.top_stack = stack_base;
+                    return Err(error);
+                }
+            }
+        }
+        let mut result = Ok(());
+        if !self.call_value(callee, args.len()) {
+            result = Err(self.take_error());
+        } else if self.frames.len() > base {
+            // Natives and initializer-less classes finish without a frame.
+            if let InterpretRuntimeError(error) = self.run(base) {
+                result = Err(error);
+            }
+        }
+        match result {
+            Ok(()) => {
+                let result = self.pop();
+                Ok(self.export(result))
+            }
+            Err(error) => {
+                self.unwind(base, stack_base);
+                Err(error)
+            }
         }
     }
 
-    pub fn init(&mut self) -> () {
-        self.top_stack = 0;
+    // Copies host data into the VM. A fresh string is unrooted, like any newly
+    // allocated object.
+    fn import(&mut self, value: &LoxValue) -> Result<Value, RuntimeError> {
+        match value {
+            LoxValue::Nil => Ok(Value::nil()),
+            LoxValue::Bool(b) => Ok(Value::bool(*b)),
+            LoxValue::Number(n) => Ok(Value::number(*n)),
+            LoxValue::String(s) => Ok(Value::obj(obj::copy_string(self, s))),
+            LoxValue::Object(Handle(root)) if root.vm == self.id => Ok(root.value),
+            LoxValue::Object(_) => Err(RuntimeError::new("Handle belongs to another VM.")),
+        }
+    }
+
+    fn export(&mut self, value: Value) -> LoxValue {
+        if value.is_nil() {
+            LoxValue::Nil
+        } else if value.is_bool() {
+            LoxValue::Bool(value.as_bool())
+        } else if value.is_number() {
+            LoxValue::Number(value.as_number())
+        } else if let Some(string) = value.downcast::<ObjString>() {
+            LoxValue::String(string.as_str().to_string())
+        } else {
+            // Drop dead handles before growing, so the ones made for short
+            // native calls don't pile up between collections.
+            if self.handles.len() == self.handles.capacity() {
+                self.handles.retain(|handle| handle.strong_count() > 0);
+            }
+            let root = Rc::new(Root { vm: self.id, value });
+            self.handles.push(Rc::downgrade(&root));
+            LoxValue::Object(Handle(root))
+        }
     }
 
-    pub fn reset_stack(&mut self) -> () {
-        self.top_stack = 0;
+    fn take_error(&mut self) -> RuntimeError {
+        self.error.take().expect("A runtime error was reported.")
     }
 
-    pub fn reset(&mut self) -> () {
-        self.chunk = Chunk::new();
-        self.ip = 0;
+    // Drops the frames and stack slots a failed call left above `base` and
+    // `stack_base`.
+    fn unwind(&mut self, base: usize, stack_base: usize) {
+        // Closures that escaped the failed code still refer to these slots.
+        self.close_upvalues(stack_base);
+        self.frames.truncate(base);
+        self.top_stack = stack_base;
     }
 
-    pub fn push(&mut self, value: Value) -> () {
-        self.stack[self.top_stack] = value;
+    pub(crate) fn push(&mut self, value: Value) {
+        if self.top_stack == self.stack.len() {
+            self.stack.push(value);
+        } else {
+            self.stack[self.top_stack] = value;
+        }
         self.top_stack += 1;
     }
 
-    pub fn pop(&mut self) -> Value {
+    pub(crate) fn pop(&mut self) -> Value {
         self.top_stack -= 1;
         self.stack[self.top_stack]
     }
 
-    pub fn peek(&self) -> Value {
+    pub(crate) fn peek(&self) -> Value {
         self.stack[self.top_stack - 1]
     }
 
-    pub fn peek_next(&self) -> Value {
+    pub(crate) fn peek_next(&self) -> Value {
         self.stack[self.top_stack - 2]
     }
 
-    pub fn free(&mut self) -> () {
-        self.chunk = Chunk::new();
-        self.ip = 0;
-        self.stack = vec![];
+    #[inline(always)]
+    fn peek_at(&self, distance: usize) -> Value {
+        self.stack[self.top_stack - 1 - distance]
+    }
+
+    pub(crate) fn alloc<T: Object>(&mut self, obj: T) -> Gc<T> {
+        if self.gc.should_collect() {
+            self.collect_garbage();
+        }
+        self.gc.alloc(obj)
+    }
+
+    pub(crate) fn find_string(&self, s: &str, hash: u32) -> Option<Gc<ObjString>> {
+        self.strings.find_string(s, hash)
     }
 
+    pub(crate) fn intern_string(&mut self, string: Gc<ObjString>) {
+        self.strings.set(string, Value::nil());
+    }
+
+    pub(crate) fn push_compiler_root(&mut self, value: Value) {
+        self.compiler_roots.push(value);
+    }
+
+    pub fn collect_garbage(&mut self) {
+        #[cfg(feature = "gc-log")]
+        let before = self.gc.stats().bytes_allocated;
+        #[cfg(feature = "gc-log")]
+        println!("-- gc begin");
+
+        self.mark_roots();
+        self.gc.trace_references();
+        // The string table holds its strings weakly.
+        self.strings.remove_white();
+        self.gc.sweep();
+
+        #[cfg(feature = "gc-log")]
+        {
+            let after = self.gc.stats().bytes_allocated;
+            println!(
+                "-- gc end, collected {} bytes (from {} to {})",
+                before - after,
+                before,
+                after
+            );
+        }
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.gc.stats()
+    }
+
+    fn mark_roots(&mut self) {
+        for &value in self.stack[..self.top_stack].iter() {
+            self.gc.mark_value(value);
+        }
+        for frame in self.frames.iter() {
+            self.gc.mark_object(frame.closure);
+        }
+        let mut upvalue = self.open_upvalues;
+        while let Some(u) = upvalue {
+            self.gc.mark_object(u);
+            upvalue = u.next.get();
+        }
+        self.gc.mark_table(&self.globals);
+        if let Some(init_string) = self.init_string {
+            self.gc.mark_object(init_string);
+        }
+        for &value in self.compiler_roots.iter() {
+            self.gc.mark_value(value);
+        }
+        self.handles.retain(|handle| handle.strong_count() > 0);
+        for handle in self.handles.iter() {
+            if let Some(root) = handle.upgrade() {
+                self.gc.mark_value(root.value);
+            }
+        }
+    }
+
+    // Runs `source` as a script. Natives may call this too, and the script
+    // then runs on top of the frames that called them.
     pub fn interpret(&mut self, source: &str) -> InterpretResult {
-        let mut compiler = Compiler::new(source, &mut self.chunk);
-        if !compiler.compile(source) {
-            eprintln!("error");
-            self.runtime_error();
-            InterpretCompileError
+        let mut compiler = Compiler::new(source, self);
+        let function = compiler.compile();
+        self.compiler_roots.clear();
+        let function = match function {
+            Ok(function) => function,
+            Err(errors) => {
+                for error in errors.iter() {
+                    self.reporter.compile_error(error);
+                }
+                return InterpretCompileError(errors);
+            }
+        };
+        let base = self.frames.len();
+        let stack_base = self.top_stack;
+        self.push(Value::obj(function));
+        let closure = obj::new_closure(self, function, vec![]);
+        self.pop();
+        self.push(Value::obj(closure));
+        let res = if self.call_closure(closure, 0) {
+            self.run(base)
         } else {
-            let res = self.run();
-            self.reset();
-            res
+            InterpretRuntimeError(self.take_error())
+        };
+        match &res {
+            InterpretRuntimeError(error) => {
+                self.reporter.runtime_error(error);
+                self.unwind(base, stack_base);
+            }
+            _ => self.top_stack = stack_base,
         }
+        res
     }
 
-    // pub fn interpret(&mut self, chunk: Chunk) -> InterpretResult {
-    //     self.chunk = chunk;
-    //     if self.chunk.code.len() > 0 {
-    //         self.ip = 0;
-    //     }
-    //     self.run()
-    // }
+    #[inline(always)]
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    #[inline(always)]
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    #[inline(always)]
+    fn chunk(&self) -> &Chunk {
+        &self.frame().closure.function.chunk
+    }
 
     #[inline(always)]
     fn get_code(&mut self) -> u8 {
-        let op = self.chunk.code[self.ip];
-        self.ip += 1;
+        let op = self.chunk().code[self.frame().ip];
+        self.frame_mut().ip += 1;
         op
     }
 
+    #[inline(always)]
+    fn read_long(&mut self) -> u16 {
+        let num = self.chunk().read_long(self.frame().ip);
+        self.frame_mut().ip += 2;
+        num
+    }
+
     #[inline(always)]
     fn read_constant(&mut self) -> Value {
         let ind = self.get_code();
-        self.chunk.get_constant(ind)
+        self.chunk().get_constant(ind)
+    }
+
+    #[inline(always)]
+    fn read_string(&mut self) -> Gc<ObjString> {
+        self.read_constant().as_obj().cast()
     }
 
     #[cfg(debug_assertions)]
+    #[allow(dead_code)]
     fn show_stack(&self) {
         print!("                  ");
         self.stack.iter().for_each(|x| {
@@ -131,128 +508,849 @@ impl VM {
         println!();
     }
 
-    pub fn runtime_error(&mut self) {
-        let instruction = if self.ip > 0 { self.ip - 1 } else { self.ip };
-        let line = self.chunk.get_line(instruction);
-        eprintln!("[line {}] in script", line);
-        self.reset_stack();
+    // Records an error with the current stack trace. The caller then unwinds
+    // by returning failure up to `run`.
+    pub(crate) fn runtime_error(&mut self, msg: &str) {
+        let stack_trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = &frame.closure.function;
+                let instruction = frame.ip.saturating_sub(1);
+                let span = function.chunk.get_span(instruction);
+                let (column, source_line) = line_at(&function.chunk.source, span.offset);
+                StackFrame {
+                    line: function.chunk.get_line(instruction),
+                    column,
+                    function: function.name.map(|name| name.as_str().to_string()),
+                    span,
+                    source_line: source_line.to_string(),
+                }
+            })
+            .collect();
+        self.error = Some(RuntimeError {
+            message: msg.to_string(),
+            stack_trace,
+        });
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> bool {
+        if let Some(closure) = callee.downcast::<ObjClosure>() {
+            self.call_closure(closure, arg_count)
+        } else if let Some(bound) = callee.downcast::<ObjBoundMethod>() {
+            self.stack[self.top_stack - arg_count - 1] = bound.receiver;
+            self.call_closure(bound.method, arg_count)
+        } else if let Some(native) = callee.downcast::<ObjNative>() {
+            self.call_native(native, arg_count)
+        } else if let Some(class) = callee.downcast::<ObjClass>() {
+            let instance = obj::new_instance(self, class);
+            self.stack[self.top_stack - arg_count - 1] = Value::obj(instance);
+            let init_string = self.init_string.expect("VM is initialized.");
+            let initializer = class.methods.borrow().get(init_string);
+            match initializer {
+                Some(initializer) => self.call_closure(initializer.as_obj().cast(), arg_count),
+                None if arg_count != 0 => {
+                    self.runtime_error(&format!("Expected 0 arguments but got {}.", arg_count));
+                    false
+                }
+                None => true,
+            }
+        } else {
+            self.runtime_error("Can only call functions and classes.");
+            false
+        }
+    }
+
+    fn call_closure(&mut self, closure: Gc<ObjClosure>, arg_count: usize) -> bool {
+        let arity = closure.function.arity;
+        if arg_count != arity {
+            self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            ));
+            return false;
+        }
+        if self.frames.len() == FRAMES_MAX || self.top_stack > STACK_MAX {
+            self.runtime_error("Stack overflow.");
+            return false;
+        }
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.top_stack - arg_count - 1,
+        });
+        true
+    }
+
+    fn call_native(&mut self, native: Gc<ObjNative>, arg_count: usize) -> bool {
+        if arg_count != native.arity {
+            self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                native.arity, arg_count
+            ));
+            return false;
+        }
+        let args: Vec<_> = (0..arg_count)
+            .map(|i| self.export(self.stack[self.top_stack - arg_count + i]))
+            .collect();
+        // The arguments stay on the stack while the native runs, so a string
+        // result can be allocated without losing them.
+        let result = (native.function)(self, &args).and_then(|result| self.import(&result));
+        match result {
+            Ok(result) => {
+                self.top_stack -= arg_count + 1;
+                self.push(result);
+                true
+            }
+            // Errors from Lox code the native called back into already carry
+            // their trace.
+            Err(error) if !error.stack_trace.is_empty() => {
+                self.error = Some(error);
+                false
+            }
+            Err(error) => {
+                self.runtime_error(&error.message);
+                false
+            }
+        }
+    }
+
+    fn invoke(&mut self, name: Gc<ObjString>, arg_count: usize) -> bool {
+        if let Some(list) = self.peek_at(arg_count).downcast::<ObjList>() {
+            return self.invoke_list_method(list, name, arg_count);
+        }
+        if let Some(map) = self.peek_at(arg_count).downcast::<ObjMap>() {
+            return self.invoke_map_method(map, name, arg_count);
+        }
+        let instance = match self.peek_at(arg_count).downcast::<ObjInstance>() {
+            Some(instance) => instance,
+            None => {
+                self.runtime_error("Only instances have methods.");
+                return false;
+            }
+        };
+        let field = instance.fields.borrow().get(name);
+        if let Some(value) = field {
+            self.stack[self.top_stack - arg_count - 1] = value;
+            return self.call_value(value, arg_count);
+        }
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: Gc<ObjClass>,
+        name: Gc<ObjString>,
+        arg_count: usize,
+    ) -> bool {
+        let method = class.methods.borrow().get(name);
+        match method {
+            Some(method) => self.call_closure(method.as_obj().cast(), arg_count),
+            None => {
+                let name = name.as_str();
+                self.runtime_error(&format!("Undefined property '{}'.", name));
+                false
+            }
+        }
+    }
+
+    // Lists have no class, so their methods are dispatched by name here.
+    fn invoke_list_method(
+        &mut self,
+        list: Gc<ObjList>,
+        name: Gc<ObjString>,
+        arg_count: usize,
+    ) -> bool {
+        let arity = match name.as_str() {
+            "push" => 1,
+            "pop" | "len" => 0,
+            "insert" => 2,
+            "remove" => 1,
+            _ => {
+                let name = name.as_str();
+                self.runtime_error(&format!("Undefined property '{}'.", name));
+                return false;
+            }
+        };
+        if arg_count != arity {
+            self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            ));
+            return false;
+        }
+        let len = list.items.borrow().len();
+        let result = match name.as_str() {
+            "push" => {
+                let item = self.peek();
+                self.gc
+                    .resize(list, |list| list.items.borrow_mut().push(item));
+                Value::nil()
+            }
+            "pop" => match list.items.borrow_mut().pop() {
+                Some(item) => item,
+                None => {
+                    self.runtime_error("Can't pop from an empty list.");
+                    return false;
+                }
+            },
+            "len" => Value::number(len as f64),
+            "insert" => match self.list_index(self.peek_next(), len, len + 1) {
+                Some(index) => {
+                    let item = self.peek();
+                    self.gc
+                        .resize(list, |list| list.items.borrow_mut().insert(index, item));
+                    Value::nil()
+                }
+                None => return false,
+            },
+            _ => match self.list_index(self.peek(), len, len) {
+                Some(index) => list.items.borrow_mut().remove(index),
+                None => return false,
+            },
+        };
+        self.top_stack -= arg_count + 1;
+        self.push(result);
+        true
+    }
+
+    fn invoke_map_method(
+        &mut self,
+        map: Gc<ObjMap>,
+        name: Gc<ObjString>,
+        arg_count: usize,
+    ) -> bool {
+        let arity = match name.as_str() {
+            "has" | "remove" => 1,
+            "keys" | "values" | "len" => 0,
+            _ => {
+                let name = name.as_str();
+                self.runtime_error(&format!("Undefined property '{}'.", name));
+                return false;
+            }
+        };
+        if arg_count != arity {
+            self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            ));
+            return false;
+        }
+        if arity == 1 && !self.check_map_key(self.peek()) {
+            return false;
+        }
+        let result = match name.as_str() {
+            "has" => Value::bool(map.table.borrow().get(self.peek()).is_some()),
+            "remove" => {
+                let key = self.peek();
+                let mut table = map.table.borrow_mut();
+                let removed = table.get(key).unwrap_or(Value::nil());
+                table.delete(key);
+                removed
+            }
+            "keys" => {
+                let keys = map.table.borrow().iter().map(|(key, _)| key).collect();
+                Value::obj(obj::new_list(self, keys))
+            }
+            "values" => {
+                let values = map.table.borrow().iter().map(|(_, value)| value).collect();
+                Value::obj(obj::new_list(self, values))
+            }
+            _ => Value::number(map.table.borrow().iter().count() as f64),
+        };
+        self.top_stack -= arg_count + 1;
+        self.push(result);
+        true
+    }
+
+    fn check_map_key(&mut self, key: Value) -> bool {
+        match VM::map_key_error(key) {
+            Some(message) => {
+                self.runtime_error(message);
+                false
+            }
+            None => true,
+        }
+    }
+
+    // Maps only accept keys that hash by value.
+    fn map_key_error(key: Value) -> Option<&'static str> {
+        if key.is_number() && key.as_number().is_nan() {
+            Some("Map key can't be NaN.")
+        } else if key.is_obj() && !key.is_obj_type::<ObjString>() {
+            Some("Map keys must be numbers, strings, booleans or nil.")
+        } else {
+            None
+        }
+    }
+
+    // Resolves a possibly negative index against a list of `len` items,
+    // accepting indices below `limit`.
+    fn list_index(&mut self, index: Value, len: usize, limit: usize) -> Option<usize> {
+        if !index.is_number() {
+            self.runtime_error("List index must be a number.");
+            return None;
+        }
+        let index = index.as_number();
+        if index.fract() != 0.0 {
+            self.runtime_error("List index must be an integer.");
+            return None;
+        }
+        let resolved = if index < 0.0 {
+            index + len as f64
+        } else {
+            index
+        };
+        if resolved < 0.0 || resolved >= limit as f64 {
+            self.runtime_error("List index out of range.");
+            return None;
+        }
+        Some(resolved as usize)
+    }
+
+    // Replaces the receiver on top of the stack with its method `name` bound to it.
+    fn bind_method(&mut self, class: Gc<ObjClass>, name: Gc<ObjString>) -> bool {
+        let method = class.methods.borrow().get(name);
+        match method {
+            Some(method) => {
+                let method = method.as_obj().cast();
+                let bound = obj::new_bound_method(self, self.peek(), method);
+                self.pop();
+                self.push(Value::obj(bound));
+                true
+            }
+            None => {
+                let name = name.as_str();
+                self.runtime_error(&format!("Undefined property '{}'.", name));
+                false
+            }
+        }
+    }
+
+    fn capture_upvalue(&mut self, location: usize) -> Gc<ObjUpvalue> {
+        let mut prev: Option<Gc<ObjUpvalue>> = None;
+        let mut upvalue = self.open_upvalues;
+        while let Some(u) = upvalue {
+            if u.location <= location {
+                break;
+            }
+            prev = upvalue;
+            upvalue = u.next.get();
+        }
+        if let Some(u) = upvalue {
+            if u.location == location {
+                return u;
+            }
+        }
+
+        let created = obj::new_upvalue(self, location, upvalue);
+        match prev {
+            Some(p) => p.next.set(Some(created)),
+            None => self.open_upvalues = Some(created),
+        }
+        created
+    }
+
+    // Hoists every open upvalue at or above `last` off the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(u) = self.open_upvalues {
+            if u.location < last {
+                break;
+            }
+            u.closed.set(Some(self.stack[u.location]));
+            self.open_upvalues = u.next.get();
+        }
+    }
+
+    fn upvalue(&self, slot: usize) -> Gc<ObjUpvalue> {
+        self.frame().closure.upvalues[slot]
     }
 
     #[cfg(not(debug_assertions))]
-    fn showStack(&self) {}
+    #[allow(dead_code)]
+    fn show_stack(&self) {}
 
-    fn run(&mut self) -> InterpretResult {
+    // Runs until the frame count drops back to `base`, leaving the returned
+    // value on the stack.
+    fn run(&mut self, base: usize) -> InterpretResult {
         loop {
             // self.show_stack();
-            // self.chunk.disassemble_instruction(self.ip);
+            // self.chunk().disassemble_instruction(self.frame().ip);
             let i = unsafe { OpCode::from_unchecked(self.get_code()) };
             match i {
                 OpCode::OpReturn => {
+                    let result = self.pop();
+                    self.close_upvalues(self.frame().slots);
+                    let frame = self.frames.pop().unwrap();
+                    self.top_stack = frame.slots;
+                    self.push(result);
+                    if self.frames.len() == base {
+                        return InterpretOk;
+                    }
+                }
+                OpCode::OpCall => {
+                    let arg_count = self.get_code() as usize;
+                    if !self.call_value(self.peek_at(arg_count), arg_count) {
+                        return InterpretRuntimeError(self.take_error());
+                    }
+                }
+                OpCode::OpPrint => {
                     print_value(self.pop());
                     println!();
-                    return InterpretOk;
+                }
+                OpCode::OpPop => {
+                    self.pop();
+                }
+                OpCode::OpGetLocal => {
+                    let slot = self.frame().slots + self.get_code() as usize;
+                    self.push(self.stack[slot]);
+                }
+                OpCode::OpSetLocal => {
+                    let slot = self.frame().slots + self.get_code() as usize;
+                    self.stack[slot] = self.peek();
+                }
+                OpCode::OpJump => {
+                    let offset = self.read_long() as usize;
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::OpJumpIfFalse => {
+                    let offset = self.read_long() as usize;
+                    if self.peek().is_falsey() {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::OpLoop => {
+                    let offset = self.read_long() as usize;
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::OpClosure => {
+                    let function = self.read_constant().as_obj().cast::<ObjFunction>();
+                    // Upvalues stay reachable while the closure is allocated:
+                    // new ones are open and the rest belong to this frame.
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = self.get_code() == 1;
+                        let index = self.get_code() as usize;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(self.frame().slots + index)
+                        } else {
+                            self.upvalue(index)
+                        };
+                        upvalues.push(upvalue);
+                    }
+                    let closure = obj::new_closure(self, function, upvalues);
+                    self.push(Value::obj(closure));
+                }
+                OpCode::OpGetUpvalue => {
+                    let slot = self.get_code() as usize;
+                    let upvalue = self.upvalue(slot);
+                    let value = match upvalue.closed.get() {
+                        Some(v) => v,
+                        None => self.stack[upvalue.location],
+                    };
+                    self.push(value);
+                }
+                OpCode::OpSetUpvalue => {
+                    let slot = self.get_code() as usize;
+                    let upvalue = self.upvalue(slot);
+                    let value = self.peek();
+                    match upvalue.closed.get() {
+                        Some(_) => upvalue.closed.set(Some(value)),
+                        None => self.stack[upvalue.location] = value,
+                    }
+                }
+                OpCode::OpCloseUpvalue => {
+                    self.close_upvalues(self.top_stack - 1);
+                    self.pop();
+                }
+                OpCode::OpClass => {
+                    let name = self.read_string();
+                    let class = obj::new_class(self, name);
+                    self.push(Value::obj(class));
+                }
+                OpCode::OpGetProperty => {
+                    let name = self.read_string();
+                    let instance = match self.peek().downcast::<ObjInstance>() {
+                        Some(instance) => instance,
+                        None => {
+                            self.runtime_error("Only instances have properties.");
+                            return InterpretRuntimeError(self.take_error());
+                        }
+                    };
+                    let field = instance.fields.borrow().get(name);
+                    if let Some(value) = field {
+                        self.pop();
+                        self.push(value);
+                    } else if !self.bind_method(instance.class, name) {
+                        return InterpretRuntimeError(self.take_error());
+                    }
+                }
+                OpCode::OpSetProperty => {
+                    let name = self.read_string();
+                    let instance = match self.peek_next().downcast::<ObjInstance>() {
+                        Some(instance) => instance,
+                        None => {
+                            self.runtime_error("Only instances have fields.");
+                            return InterpretRuntimeError(self.take_error());
+                        }
+                    };
+                    let value = self.pop();
+                    self.gc.resize(instance, |instance| {
+                        instance.fields.borrow_mut().set(name, value)
+                    });
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::OpMethod => {
+                    let name = self.read_string();
+                    let method = self.peek();
+                    let class = self.peek_next().as_obj().cast::<ObjClass>();
+                    self.gc
+                        .resize(class, |class| class.methods.borrow_mut().set(name, method));
+                    self.pop();
+                }
+                OpCode::OpInvoke => {
+                    let name = self.read_string();
+                    let arg_count = self.get_code() as usize;
+                    if !self.invoke(name, arg_count) {
+                        return InterpretRuntimeError(self.take_error());
+                    }
+                }
+                OpCode::OpDup => self.push(self.peek()),
+                OpCode::OpDup2 => {
+                    self.push(self.peek_next());
+                    self.push(self.peek_next());
+                }
+                OpCode::OpBuildList => {
+                    let count = self.get_code() as usize;
+                    // The items stay on the stack until the list owns them.
+                    let items = self.stack[self.top_stack - count..self.top_stack].to_vec();
+                    let list = obj::new_list(self, items);
+                    self.top_stack -= count;
+                    self.push(Value::obj(list));
+                }
+                OpCode::OpBuildMap => {
+                    let count = self.get_code() as usize;
+                    let first = self.top_stack - count * 2;
+                    for i in 0..count {
+                        if !self.check_map_key(self.stack[first + i * 2]) {
+                            return InterpretRuntimeError(self.take_error());
+                        }
+                    }
+                    // The entries stay on the stack until the map owns them.
+                    let map = obj::new_map(self);
+                    let entries = &self.stack[first..self.top_stack];
+                    self.gc.resize(map, |map| {
+                        let mut table = map.table.borrow_mut();
+                        for entry in entries.chunks(2) {
+                            table.set(entry[0], entry[1]);
+                        }
+                    });
+                    self.top_stack = first;
+                    self.push(Value::obj(map));
+                }
+                OpCode::OpIndexGet => {
+                    let value = if let Some(list) = self.peek_next().downcast::<ObjList>() {
+                        let len = list.items.borrow().len();
+                        match self.list_index(self.peek(), len, len) {
+                            Some(index) => list.items.borrow()[index],
+                            None => return InterpretRuntimeError(self.take_error()),
+                        }
+                    } else if let Some(map) = self.peek_next().downcast::<ObjMap>() {
+                        if !self.check_map_key(self.peek()) {
+                            return InterpretRuntimeError(self.take_error());
+                        }
+                        let value = map.table.borrow().get(self.peek());
+                        match value {
+                            Some(value) => value,
+                            None => {
+                                self.runtime_error("Key not found in map.");
+                                return InterpretRuntimeError(self.take_error());
+                            }
+                        }
+                    } else {
+                        self.runtime_error("Only lists and maps can be indexed.");
+                        return InterpretRuntimeError(self.take_error());
+                    };
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::OpIndexSet => {
+                    let value = self.peek();
+                    if let Some(list) = self.peek_at(2).downcast::<ObjList>() {
+                        let len = list.items.borrow().len();
+                        match self.list_index(self.peek_next(), len, len) {
+                            Some(index) => list.items.borrow_mut()[index] = value,
+                            None => return InterpretRuntimeError(self.take_error()),
+                        }
+                    } else if let Some(map) = self.peek_at(2).downcast::<ObjMap>() {
+                        if !self.check_map_key(self.peek_next()) {
+                            return InterpretRuntimeError(self.take_error());
+                        }
+                        let key = self.peek_next();
+                        self.gc
+                            .resize(map, |map| map.table.borrow_mut().set(key, value));
+                    } else {
+                        self.runtime_error("Only lists and maps can be indexed.");
+                        return InterpretRuntimeError(self.take_error());
+                    }
+                    self.top_stack -= 3;
+                    self.push(value);
+                }
+                OpCode::OpInherit => {
+                    let superclass = match self.peek_next().downcast::<ObjClass>() {
+                        Some(superclass) => superclass,
+                        None => {
+                            self.runtime_error("Superclass must be a class.");
+                            return InterpretRuntimeError(self.take_error());
+                        }
+                    };
+                    let subclass = self.peek().as_obj().cast::<ObjClass>();
+                    self.gc.resize(subclass, |subclass| {
+                        subclass
+                            .methods
+                            .borrow_mut()
+                            .add_all(&superclass.methods.borrow())
+                    });
+                    self.pop();
+                }
+                OpCode::OpGetSuper => {
+                    let name = self.read_string();
+                    let superclass = self.pop().as_obj().cast::<ObjClass>();
+                    if !self.bind_method(superclass, name) {
+                        return InterpretRuntimeError(self.take_error());
+                    }
+                }
+                OpCode::OpSuperInvoke => {
+                    let name = self.read_string();
+                    let arg_count = self.get_code() as usize;
+                    let superclass = self.pop().as_obj().cast::<ObjClass>();
+                    if !self.invoke_from_class(superclass, name, arg_count) {
+                        return InterpretRuntimeError(self.take_error());
+                    }
+                }
+                OpCode::OpDefineGlobal => {
+                    let name = self.read_string();
+                    let value = self.pop();
+                    self.globals.set(name, value);
+                }
+                OpCode::OpGetGlobal => {
+                    let name = self.read_string();
+                    match self.globals.get(name) {
+                        Some(value) => self.push(value),
+                        None => {
+                            let name = name.as_str();
+                            self.runtime_error(&format!("Undefined variable '{}'.", name));
+                            return InterpretRuntimeError(self.take_error());
+                        }
+                    }
+                }
+                OpCode::OpSetGlobal => {
+                    let name = self.read_string();
+                    let value = self.peek();
+                    if self.globals.set(name, value) {
+                        self.globals.delete(name);
+                        let name = name.as_str();
+                        self.runtime_error(&format!("Undefined variable '{}'.", name));
+                        return InterpretRuntimeError(self.take_error());
+                    }
                 }
                 OpCode::OpConstant => {
                     let constant = self.read_constant();
                     self.push(constant);
                 }
                 OpCode::OpNegate => {
-                    if let Value::Number(v) = self.peek() {
-                        self.pop();
-                        self.push(Value::Number(-v));
+                    if self.peek().is_number() {
+                        let v = self.pop().as_number();
+                        self.push(Value::number(-v));
                     } else {
-                        return InterpretRuntimeError;
+                        self.runtime_error("Operand must be a number.");
+                        return InterpretRuntimeError(self.take_error());
                     }
                 }
                 OpCode::OpAdd => {
                     let a = self.peek();
                     let b = self.peek_next();
-                    match (a, b) {
-                        (Value::Number(_), Value::Number(_)) => binary_op!(self, Number, +),
-                        (Value::Obj(o1), Value::Obj(o2)) => unsafe {
-                            match ((*o1).get_type(), (*o2).get_type()) {
-                                (ObjType::OString, ObjType::OString) => {
-                                    let s = VM::concatenate(o2, o1);
-                                    self.pop();
-                                    self.pop();
-                                    self.push(Value::Obj(s));
-                                }
-                            }
-                        },
-                        _ => {
-                            eprintln!("Operands must be numbers or strings.");
-                            self.runtime_error();
-                            return InterpretRuntimeError;
-                        }
+                    if a.is_number() && b.is_number() {
+                        binary_op!(self, number, +)
+                    } else if a.is_obj_type::<ObjString>() && b.is_obj_type::<ObjString>() {
+                        let s = self.concatenate(b.as_obj().cast(), a.as_obj().cast());
+                        self.pop();
+                        self.pop();
+                        self.push(Value::obj(s));
+                    } else {
+                        self.runtime_error("Operands must be numbers or strings.");
+                        return InterpretRuntimeError(self.take_error());
                     }
                 }
-                OpCode::OpSubtract => binary_op!(self, Number, -),
-                OpCode::OpMultiply => binary_op!(self, Number, *),
-                OpCode::OpDivide => binary_op!(self, Number, /),
-                OpCode::OpNil => self.push(Value::Empty),
-                OpCode::OpTrue => self.push(Value::Bool(true)),
-                OpCode::OpFalse => self.push(Value::Bool(false)),
+                OpCode::OpSubtract => binary_op!(self, number, -),
+                OpCode::OpMultiply => binary_op!(self, number, *),
+                OpCode::OpDivide => binary_op!(self, number, /),
+                OpCode::OpNil => self.push(Value::nil()),
+                OpCode::OpTrue => self.push(Value::bool(true)),
+                OpCode::OpFalse => self.push(Value::bool(false)),
                 OpCode::OpNot => {
                     let v = self.pop();
-                    self.push(Value::Bool(VM::is_falsey(v)))
+                    self.push(Value::bool(v.is_falsey()))
                 }
                 OpCode::OpEqual => {
                     let a = self.pop();
                     let b = self.pop();
-                    self.push(Value::Bool(VM::values_equal(a, b)))
+                    self.push(Value::bool(a == b))
                 }
-                OpCode::OpGreater => binary_op!(self, Bool, >),
-                OpCode::OpLess => binary_op!(self, Bool, <),
-                OpCode::OpGreaterEqual => binary_op!(self, Bool, >=),
-                OpCode::OpLessEqual => binary_op!(self, Bool, <=),
+                OpCode::OpGreater => binary_op!(self, bool, >),
+                OpCode::OpLess => binary_op!(self, bool, <),
+                OpCode::OpGreaterEqual => binary_op!(self, bool, >=),
+                OpCode::OpLessEqual => binary_op!(self, bool, <=),
                 OpCode::OpNotEqual => {
                     let a = self.pop();
                     let b = self.pop();
-                    self.push(Value::Bool(!VM::values_equal(a, b)))
+                    self.push(Value::bool(a != b))
                 }
             };
         }
     }
 
-    fn values_equal(a: Value, b: Value) -> bool {
-        match (a, b) {
-            (Value::Bool(b1), Value::Bool(b2)) => b1 == b2,
-            (Value::Number(n1), Value::Number(n2)) => n1 == n2,
-            (Value::Empty, Value::Empty) => true,
-            (Value::Obj(o1), Value::Obj(o2)) => unsafe {
-                match ((*o1).get_type(), (*o2).get_type()) {
-                    (ObjType::OString, ObjType::OString) => {
-                        let s1 = (o1 as *const ObjString).read();
-                        let s2 = (o2 as *const ObjString).read();
-                        s1.len == s2.len && s1.chars == s2.chars
-                    }
-                    _ => false,
-                }
-            },
-            _ => false,
-        }
+    fn concatenate(&mut self, s1: Gc<ObjString>, s2: Gc<ObjString>) -> Gc<ObjString> {
+        let mut chars = String::with_capacity(s1.chars.len() + s2.chars.len());
+        chars.push_str(s1.as_str());
+        chars.push_str(s2.as_str());
+        obj::take_string(self, chars)
     }
+}
 
-    fn is_falsey(v: Value) -> bool {
-        match v {
-            Value::Bool(b) => !b,
-            Value::Number(_) => false,
-            Value::Empty => true,
-            Value::Obj(_) => false,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs `source` and checks the heap is back to where it started once
+    // everything the script allocated is unreachable.
+    fn assert_no_leaks(source: &str) -> InterpretResult {
+        let mut vm = VM::new();
+        vm.collect_garbage();
+        let before = vm.heap_stats();
+        let result = vm.interpret(source);
+        vm.collect_garbage();
+        let after = vm.heap_stats();
+        assert_eq!(after.objects, before.objects);
+        assert_eq!(after.bytes_allocated, before.bytes_allocated);
+        result
+    }
+
+    #[test]
+    fn frees_everything_a_script_leaves_behind() {
+        let result = assert_no_leaks(
+            "{
+              fun makeCounter() {
+                var i = 0;
+                fun count() { i = i + 1; return i; }
+                return count;
+              }
+              var counter = makeCounter();
+              class Point {
+                init(x, y) { this.x = x; this.y = y; }
+                sum() { return this.x + this.y; }
+              }
+              class Point3 < Point {}
+              for (var i = 0; i < 100; i = i + 1) {
+                var sum = Point3(i, counter()).sum;
+                var name = \"n\" + \"ame\";
+              }
+            }",
+        );
+        assert!(matches!(result, InterpretOk));
+    }
+
+    #[test]
+    fn frees_everything_after_errors() {
+        let result = assert_no_leaks(
+            "{
+              fun fail(s) { return s + 1; }
+              var s = \"a\" + \"b\";
+              fail(s);
+            }",
+        );
+        assert!(matches!(result, InterpretRuntimeError(_)));
+        let result = assert_no_leaks("fun f() { var s = \"str\"; return s + ; }");
+        assert!(matches!(result, InterpretCompileError(_)));
     }
 
-    fn concatenate(o1: *mut dyn Object, o2: *mut dyn Object) -> *mut ObjString {
-        unsafe {
-            let s1 = (o1 as *const ObjString).read();
-            let s2 = (o2 as *const ObjString).read();
-            let o = GC::alloc(Layout::new::<ObjString>()) as *mut ObjString;
-            (*o).len = s1.len + s2.len;
-            let chars = GC::alloc(Layout::array::<u8>((*o).len).unwrap());
-            std::ptr::copy(s1.chars, chars, s1.len);
-            std::ptr::copy(s2.chars, chars.offset(s1.len as isize), s2.len);
-            (*o).chars = chars;
-            o
+    #[test]
+    fn counts_lists_and_tables_that_grow() {
+        let mut vm = VM::new();
+        let result = vm.interpret(
+            "var l = [];
+            for (var i = 0; i < 1000; i = i + 1) l.push(i);",
+        );
+        assert!(matches!(result, InterpretOk));
+        vm.collect_garbage();
+        let full = vm.heap_stats().bytes_allocated;
+        assert!(matches!(vm.interpret("l = nil;"), InterpretOk));
+        vm.collect_garbage();
+        let freed = full - vm.heap_stats().bytes_allocated;
+        assert!(freed >= 1000 * size_of::<Value>(), "{}", freed);
+
+        let result = assert_no_leaks(
+            "{
+              class A { a() {} b() {} c() {} d() {} e() {} f() {} g() {} }
+              class B < A {}
+              var b = B();
+              b.f1 = 1; b.f2 = 2; b.f3 = 3; b.f4 = 4; b.f5 = 5; b.f6 = 6; b.f7 = 7;
+              var l = [1];
+              for (var i = 0; i < 100; i = i + 1) l.insert(0, i);
+              var m = {1: 1};
+              for (var i = 0; i < 100; i = i + 1) m[i] = {i: l};
+            }",
+        );
+        assert!(matches!(result, InterpretOk));
+    }
+
+    fn shout(_vm: &mut VM, args: &[LoxValue]) -> Result<LoxValue, RuntimeError> {
+        match &args[0] {
+            LoxValue::String(s) => Ok(LoxValue::String(s.to_uppercase())),
+            _ => Err(RuntimeError::new("Can only shout strings.")),
         }
     }
+
+    #[test]
+    fn natives_take_arguments_and_raise_errors() {
+        let mut vm = VM::new();
+        vm.define_native("shout", 1, shout);
+        let result = vm.interpret("if (shout(\"a\" + \"b\") != \"AB\") nil + 1;");
+        assert!(matches!(result, InterpretOk));
+        assert!(matches!(
+            vm.interpret("shout(1);"),
+            InterpretRuntimeError(_)
+        ));
+        assert!(matches!(vm.interpret("shout();"), InterpretRuntimeError(_)));
+        assert!(matches!(vm.interpret("print shout(\"ok\");"), InterpretOk));
+    }
+
+    #[test]
+    fn failed_calls_close_their_upvalues() {
+        let mut vm = VM::new();
+        let result = vm.interpret(
+            "class Box {}
+            var f;
+            fun g() {
+              var x = Box();
+              x.value = 42;
+              fun h() { return x; }
+              f = h;
+              nil + 1;
+            }
+            g();",
+        );
+        assert!(matches!(result, InterpretRuntimeError(_)));
+        vm.collect_garbage();
+        assert!(matches!(
+            vm.interpret("var junk = \"a\" + \"b\";"),
+            InterpretOk
+        ));
+        vm.collect_garbage();
+        let result = vm.interpret("if (f().value != 42) nil + 1;");
+        assert!(matches!(result, InterpretOk));
+    }
 }
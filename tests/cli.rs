@@ -0,0 +1,98 @@
+mod common;
+
+use common::is_noise;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// Writes `source` to a script file named after the test and runs it.
+fn run_script(name: &str, source: &str) -> Output {
+    let path = env::temp_dir().join(format!("rustylox-{}-{}.lox", name, std::process::id()));
+    fs::write(&path, source).unwrap();
+    let output = run(std::slice::from_ref(&path));
+    fs::remove_file(path).unwrap();
+    output
+}
+
+fn run(args: &[PathBuf]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rustylox"))
+        .args(args)
+        .output()
+        .expect("Could not start the interpreter")
+}
+
+// What the script printed, without the output of debugging features.
+fn stdout(output: &Output) -> String {
+    let stdout = String::from_utf8(output.stdout.clone()).unwrap();
+    stdout
+        .lines()
+        .filter(|line| !is_noise(line))
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+#[test]
+fn runs_a_script_file() {
+    let output = run_script("ok", "var a = \"lox\";\nprint a + \"!\";\nprint 1 + 2;\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "lox!\n3\n");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn compile_errors_exit_with_65() {
+    let output = run_script("compile", "print 1;\nvar = 2;\nprint;\n");
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(stdout(&output), "");
+    let stderr = stderr(&output);
+    assert!(
+        stderr.contains("error: Expected variable name.\n --> 2:5"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("error: Expected expression.\n --> 3:6"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn runtime_errors_exit_with_70() {
+    let output = run_script(
+        "runtime",
+        "print 1;\nfun f() { return -nil; }\nf();\nprint 2;\n",
+    );
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "1\n");
+    let stderr = stderr(&output);
+    assert!(
+        stderr.starts_with("error: Operand must be a number.\n"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.ends_with("[line 2] in f()\n[line 3] in script\n"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn missing_files_exit_with_74() {
+    let output = run(&[PathBuf::from("no/such/script.lox")]);
+    assert_eq!(output.status.code(), Some(74));
+    assert_eq!(stderr(&output), "Could not open file no/such/script.lox\n");
+}
+
+#[test]
+fn extra_arguments_exit_with_64() {
+    let output = run(&[PathBuf::from("a.lox"), PathBuf::from("b.lox")]);
+    assert_eq!(output.status.code(), Some(64));
+    assert_eq!(stderr(&output), "Usage: rustylox [path]\n");
+}
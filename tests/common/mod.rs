@@ -0,0 +1,8 @@
+// Disassembler output from the print-code feature and the gc-log trace.
+pub fn is_noise(line: &str) -> bool {
+    let bytes = line.as_bytes();
+    line.starts_with("0x")
+        || line.starts_with("-- gc ")
+        || (line.starts_with("== ") && line.ends_with(" =="))
+        || (bytes.len() > 4 && bytes[..4].iter().all(u8::is_ascii_digit) && bytes[4] == b' ')
+}
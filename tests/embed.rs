@@ -0,0 +1,414 @@
+use rustylox::{CompileError, ErrorReporter, InterpretResult, LoxValue, RuntimeError, VM};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+fn number(value: Result<LoxValue, RuntimeError>) -> f64 {
+    f64::try_from(value.unwrap()).unwrap()
+}
+
+#[test]
+fn globals_cross_the_boundary() {
+    let mut vm = VM::new();
+    vm.set_global("n", 1.5).unwrap();
+    vm.set_global("flag", true).unwrap();
+    vm.set_global("name", "lox").unwrap();
+    vm.set_global("nothing", ()).unwrap();
+    let result = vm.interpret(
+        "var out = n * 2;
+        var greeting = name + \"!\";
+        var both = flag and nothing == nil;",
+    );
+    assert_eq!(result, InterpretResult::InterpretOk);
+    assert_eq!(vm.get_global("out").unwrap(), LoxValue::Number(3.0));
+    assert_eq!(vm.get_global("greeting").unwrap(), "lox!".into());
+    assert_eq!(vm.get_global("both").unwrap(), LoxValue::Bool(true));
+    let error = vm.get_global("missing").unwrap_err();
+    assert_eq!(error.message, "Undefined variable 'missing'.");
+}
+
+#[test]
+fn call_function_runs_lox_code() {
+    let mut vm = VM::new();
+    vm.interpret(
+        "fun add(a, b) { return a + b; }
+        fun fail() { return nil + 1; }
+        class Point { init(x) { this.x = x; } }",
+    );
+    let sum = vm.call_function("add", &[1.0.into(), 2.0.into()]);
+    assert_eq!(number(sum), 3.0);
+    let joined = vm.call_function("add", &["a".into(), "b".into()]).unwrap();
+    assert_eq!(String::try_from(joined).unwrap(), "ab");
+
+    let error = vm.call_function("add", &[1.0.into()]).unwrap_err();
+    assert_eq!(error.message, "Expected 2 arguments but got 1.");
+    let error = vm.call_function("fail", &[]).unwrap_err();
+    assert_eq!(error.message, "Operands must be numbers or strings.");
+    let error = vm.call_function("nope", &[]).unwrap_err();
+    assert_eq!(error.message, "Undefined variable 'nope'.");
+
+    // Failed calls leave nothing behind for later ones.
+    let point = vm.call_function("Point", &[4.0.into()]).unwrap();
+    vm.set_global("p", point).unwrap();
+    assert_eq!(vm.interpret("var x = p.x;"), InterpretResult::InterpretOk);
+    assert_eq!(vm.get_global("x").unwrap(), LoxValue::Number(4.0));
+}
+
+#[test]
+fn handles_keep_objects_alive() {
+    let mut vm = VM::new();
+    vm.interpret(
+        "var list = [1, \"two\", [3]];
+        fun makeCounter() { var i = 0; fun count() { i = i + 1; return i; } return count; }
+        var counter = makeCounter();",
+    );
+    let list = vm.get_global("list").unwrap();
+    let counter = vm.get_global("counter").unwrap();
+    assert!(matches!(list, LoxValue::Object(_)));
+    vm.interpret("list = nil; counter = nil;");
+    vm.collect_garbage();
+    vm.interpret("for (var i = 0; i < 1000; i = i + 1) { var junk = [i, \"x\" + \"y\"]; }");
+    vm.collect_garbage();
+
+    let items = vm.list_items(&list).unwrap();
+    assert_eq!(items[..2], [LoxValue::Number(1.0), "two".into()]);
+    assert_eq!(vm.list_items(&items[2]).unwrap(), [LoxValue::Number(3.0)]);
+    assert_eq!(number(vm.call(&counter, &[])), 1.0);
+    assert_eq!(number(vm.call(&counter, &[])), 2.0);
+
+    // Once the host lets go, the objects can be collected.
+    let before = vm.heap_stats().objects;
+    drop(items);
+    drop(list);
+    drop(counter);
+    vm.collect_garbage();
+    assert!(vm.heap_stats().objects < before);
+}
+
+#[test]
+fn lists_and_maps_from_the_host() {
+    let mut vm = VM::new();
+    let list = vm.new_list(&[1.0.into(), "a".into()]).unwrap();
+    let map = vm
+        .new_map(&[("key".into(), list.clone()), (2.0.into(), true.into())])
+        .unwrap();
+    vm.set_global("m", map).unwrap();
+    assert_eq!(
+        vm.interpret("m[\"key\"].push(m[2]); var len = m[\"key\"].len();"),
+        InterpretResult::InterpretOk
+    );
+    assert_eq!(vm.get_global("len").unwrap(), LoxValue::Number(3.0));
+    assert_eq!(vm.list_items(&list).unwrap()[2], LoxValue::Bool(true));
+
+    let map = vm.get_global("m").unwrap();
+    let mut entries = vm.map_entries(&map).unwrap();
+    entries.sort_by_key(|(key, _)| matches!(key, LoxValue::String(_)));
+    assert_eq!(entries[0], (2.0.into(), true.into()));
+    assert_eq!(entries[1].0, "key".into());
+
+    let error = vm.new_map(&[(list.clone(), ().into())]).unwrap_err();
+    assert_eq!(
+        error.message,
+        "Map keys must be numbers, strings, booleans or nil."
+    );
+    assert_eq!(vm.list_items(&map).unwrap_err().message, "Expected a list.");
+    assert_eq!(
+        vm.map_entries(&1.0.into()).unwrap_err().message,
+        "Expected a map."
+    );
+}
+
+#[test]
+fn handles_belong_to_one_vm() {
+    let mut first = VM::new();
+    let mut second = VM::new();
+    let list = first.new_list(&[]).unwrap();
+    let error = second.set_global("list", list.clone()).unwrap_err();
+    assert_eq!(error.message, "Handle belongs to another VM.");
+    assert!(second.list_items(&list).is_err());
+    assert!(first.list_items(&list).unwrap().is_empty());
+}
+
+fn sum(vm: &mut VM, args: &[LoxValue]) -> Result<LoxValue, RuntimeError> {
+    let mut total = 0.0;
+    for item in vm.list_items(&args[0])? {
+        total += f64::try_from(item)?;
+    }
+    Ok(LoxValue::Number(total))
+}
+
+fn range(vm: &mut VM, args: &[LoxValue]) -> Result<LoxValue, RuntimeError> {
+    let end = f64::try_from(args[0].clone())? as usize;
+    let items: Vec<_> = (0..end).map(|i| LoxValue::Number(i as f64)).collect();
+    vm.new_list(&items)
+}
+
+fn apply(vm: &mut VM, args: &[LoxValue]) -> Result<LoxValue, RuntimeError> {
+    vm.call(&args[0], &args[1..])
+}
+
+#[test]
+fn natives_take_and_return_objects() {
+    let mut vm = VM::new();
+    vm.define_native("sum", 1, sum);
+    vm.define_native("range", 1, range);
+    vm.define_native("apply", 2, apply);
+    let result = vm.interpret(
+        "var total = sum(range(5));
+        fun twice(x) { return x * 2; }
+        var doubled = apply(twice, 21);
+        var made = apply(range, 3).len();",
+    );
+    assert_eq!(result, InterpretResult::InterpretOk);
+    assert_eq!(vm.get_global("total").unwrap(), LoxValue::Number(10.0));
+    assert_eq!(vm.get_global("doubled").unwrap(), LoxValue::Number(42.0));
+    assert_eq!(vm.get_global("made").unwrap(), LoxValue::Number(3.0));
+
+    let result = vm.interpret("sum([1, \"x\"]);");
+    assert!(matches!(result, InterpretResult::InterpretRuntimeError(_)));
+    let result = vm.interpret("apply(twice, nil);");
+    assert!(matches!(result, InterpretResult::InterpretRuntimeError(_)));
+    assert_eq!(
+        vm.interpret("var ok = sum([]);"),
+        InterpretResult::InterpretOk
+    );
+}
+
+fn eval(vm: &mut VM, args: &[LoxValue]) -> Result<LoxValue, RuntimeError> {
+    let source = String::try_from(args[0].clone())?;
+    Ok(LoxValue::Bool(
+        vm.interpret(&source) == InterpretResult::InterpretOk,
+    ))
+}
+
+#[test]
+fn natives_can_run_scripts() {
+    let mut vm = VM::new();
+    vm.define_native("eval", 1, eval);
+    let result = vm.interpret(
+        "var x = 1;
+        fun run(source) { var before = \"local\"; var ok = eval(source); return [ok, before]; }
+        var first = run(\"x = x + 1;\");
+        var second = run(\"nil + 1;\");
+        var third = run(\"var;\");
+        var after = x;",
+    );
+    assert_eq!(result, InterpretResult::InterpretOk);
+    assert_eq!(vm.get_global("after").unwrap(), LoxValue::Number(2.0));
+    for (name, ok) in [("first", true), ("second", false), ("third", false)].iter() {
+        let pair = vm.get_global(name).unwrap();
+        let pair = vm.list_items(&pair).unwrap();
+        assert_eq!(pair, [LoxValue::Bool(*ok), "local".into()]);
+    }
+}
+
+#[derive(Default)]
+struct Collect(Rc<RefCell<Vec<String>>>);
+
+impl ErrorReporter for Collect {
+    fn compile_error(&mut self, error: &CompileError) {
+        self.0.borrow_mut().push(error.to_string());
+    }
+
+    fn runtime_error(&mut self, error: &RuntimeError) {
+        self.0.borrow_mut().push(error.to_string());
+    }
+}
+
+fn compile_errors(vm: &mut VM, source: &str) -> Vec<CompileError> {
+    match vm.interpret(source) {
+        InterpretResult::InterpretCompileError(errors) => errors,
+        _ => panic!("expected a compile error"),
+    }
+}
+
+#[test]
+fn compile_errors_are_returned_and_reported() {
+    let mut vm = VM::new();
+    let reported = Rc::new(RefCell::new(vec![]));
+    vm.set_reporter(Box::new(Collect(reported.clone())));
+    let errors = compile_errors(&mut vm, "var a = 1;\n  var = 2;");
+    assert_eq!(errors.len(), 1);
+    assert_eq!((errors[0].line, errors[0].column), (2, 7));
+    assert_eq!(errors[0].lexeme, "=");
+    assert_eq!(errors[0].message, "Expected variable name.");
+    let error = compile_errors(&mut vm, "print 1 @ 3;").remove(0);
+    assert_eq!(
+        error.to_string(),
+        "[line 1] Error at @: Unexpected character."
+    );
+    let error = compile_errors(&mut vm, "print").remove(0);
+    assert_eq!(
+        error.to_string(),
+        "[line 1] Error at end: Expected expression."
+    );
+    assert_eq!(reported.borrow().len(), 3);
+    assert_eq!(reported.borrow()[2], error.to_string());
+}
+
+#[test]
+fn runtime_errors_carry_a_stack_trace() {
+    let mut vm = VM::new();
+    let reported = Rc::new(RefCell::new(vec![]));
+    vm.set_reporter(Box::new(Collect(reported.clone())));
+    let source = "fun inner() {\n  return -\"x\";\n}\nfun outer() { inner(); }\nouter();";
+    let error = match vm.interpret(source) {
+        InterpretResult::InterpretRuntimeError(error) => error,
+        _ => panic!("expected a runtime error"),
+    };
+    assert_eq!(error.message, "Operand must be a number.");
+    let trace: Vec<_> = error
+        .stack_trace
+        .iter()
+        .map(|frame| frame.to_string())
+        .collect();
+    assert_eq!(
+        trace,
+        [
+            "[line 2] in inner()",
+            "[line 4] in outer()",
+            "[line 5] in script"
+        ]
+    );
+    assert_eq!(error.stack_trace[2].function, None);
+    assert_eq!(*reported.borrow(), ["Operand must be a number."]);
+
+    // Errors from the embedding API are returned without being reported.
+    let error = vm.call_function("inner", &[]).unwrap_err();
+    assert_eq!(error.stack_trace.len(), 1);
+    assert_eq!(reported.borrow().len(), 1);
+}
+
+fn messages(vm: &mut VM, source: &str) -> Vec<String> {
+    let errors = compile_errors(vm, source);
+    errors.iter().map(|error| error.to_string()).collect()
+}
+
+#[test]
+fn independent_compile_errors_are_all_reported() {
+    let mut vm = VM::new();
+    assert_eq!(
+        messages(&mut vm, "var = 1;\nprint 2\nfun f( { }\nprint 3;"),
+        [
+            "[line 1] Error at =: Expected variable name.",
+            "[line 3] Error at fun: Expected ; after value.",
+            "[line 3] Error at {: Expected parameter name.",
+        ]
+    );
+    // An error inside a block doesn't hide one after it.
+    assert_eq!(
+        messages(&mut vm, "{ var a = ; }\nprint 1 +;"),
+        [
+            "[line 1] Error at ;: Expected expression.",
+            "[line 2] Error at ;: Expected expression.",
+        ]
+    );
+}
+
+#[test]
+fn class_bodies_recover_from_errors() {
+    let mut vm = VM::new();
+    assert_eq!(
+        messages(&mut vm, "class A { var x; } print 1;\nvar;"),
+        [
+            "[line 1] Error at var: Expected method name.",
+            "[line 2] Error at ;: Expected variable name.",
+        ]
+    );
+    assert_eq!(
+        messages(&mut vm, "class A { 1 }\nprint 2;\nprint;"),
+        [
+            "[line 1] Error at 1: Expected method name.",
+            "[line 3] Error at ;: Expected expression.",
+        ]
+    );
+    assert_eq!(
+        messages(&mut vm, "class A { m(,) { print 1; } n() {} }\nprint;"),
+        [
+            "[line 1] Error at ,: Expected parameter name.",
+            "[line 2] Error at ;: Expected expression.",
+        ]
+    );
+    assert_eq!(
+        messages(&mut vm, "class A print 1;\nprint;"),
+        [
+            "[line 1] Error at print: Expected { before class body.",
+            "[line 2] Error at ;: Expected expression.",
+        ]
+    );
+}
+
+fn runtime_error(vm: &mut VM, source: &str) -> RuntimeError {
+    match vm.interpret(source) {
+        InterpretResult::InterpretRuntimeError(error) => error,
+        _ => panic!("expected a runtime error"),
+    }
+}
+
+#[test]
+fn columns_count_characters() {
+    let mut vm = VM::new();
+    let error = compile_errors(&mut vm, "var é = \"ü\" @;").remove(0);
+    assert_eq!((error.line, error.column), (1, 5));
+    assert_eq!(error.span.len, "é".len());
+    let error = compile_errors(&mut vm, "print \"ü\" ü;").remove(0);
+    assert_eq!((error.column, error.lexeme.as_str()), (11, "ü"));
+    let error = compile_errors(&mut vm, "print \"ü\" + ;").remove(0);
+    assert_eq!((error.column, error.lexeme.as_str()), (13, ";"));
+}
+
+#[test]
+fn tokens_report_the_line_they_start_on() {
+    let mut vm = VM::new();
+    let error = compile_errors(&mut vm, "var x = 1 \"a\nb\";").remove(0);
+    assert_eq!((error.line, error.column), (1, 11));
+    assert_eq!(error.source_line, "var x = 1 \"a");
+    let error = compile_errors(&mut vm, "print 1;\n/* a\n b */ print +;").remove(0);
+    assert_eq!((error.line, error.column), (3, 13));
+}
+
+#[test]
+fn compile_errors_render_a_snippet() {
+    let mut vm = VM::new();
+    let error = compile_errors(&mut vm, "var a = 1;\nvar b = (a +  \"é\";").remove(0);
+    assert_eq!(
+        error.render(),
+        "error: Expected ) after expression.\n \
+         --> 2:18\n  \
+         |\n\
+         2 | var b = (a +  \"é\";\n  \
+         |                  ^"
+    );
+    let error = compile_errors(&mut vm, "print 1 +\n\n").remove(0);
+    assert_eq!(
+        error.render(),
+        "error: Expected expression.\n --> 1:10\n  |\n1 | print 1 +\n  |          ^"
+    );
+}
+
+#[test]
+fn runtime_errors_point_at_the_failing_code() {
+    let mut vm = VM::new();
+    let error = runtime_error(&mut vm, "var s = \"ab\ncé\"; print s - 1;");
+    let frame = &error.stack_trace[0];
+    assert_eq!((frame.line, frame.column), (2, 14));
+    assert_eq!(frame.source_line, "cé\"; print s - 1;");
+    assert_eq!(
+        error.render(),
+        "error: Operands must be numbers.\n \
+         --> 2:14\n  \
+         |\n\
+         2 | cé\"; print s - 1;\n  \
+         |              ^\n\
+         [line 2] in script"
+    );
+
+    let source = "fun f(x) {\n  return x.missing;\n}\nf(nil);";
+    let error = runtime_error(&mut vm, source);
+    let spans: Vec<_> = error
+        .stack_trace
+        .iter()
+        .map(|frame| (frame.line, frame.column, frame.span.len))
+        .collect();
+    assert_eq!(spans, [(2, 12, "missing".len()), (4, 2, 1)]);
+}
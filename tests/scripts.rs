@@ -0,0 +1,528 @@
+mod common;
+
+use common::is_noise;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// Feeds each line to the REPL and returns what the program printed, without
+// prompts or the bytecode the print-code feature dumps, along with stderr.
+fn repl(lines: &[&str]) -> (Vec<String>, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rustylox"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Could not start the interpreter");
+    {
+        let stdin = child.stdin.as_mut().unwrap();
+        for line in lines {
+            writeln!(stdin, "{}", line).unwrap();
+        }
+        writeln!(stdin, ":exit").unwrap();
+    }
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let printed = stdout
+        .lines()
+        .map(|line| line.trim_start_matches("> "))
+        .filter(|line| !line.is_empty() && !is_noise(line))
+        .map(String::from)
+        .collect();
+    (printed, String::from_utf8(output.stderr).unwrap())
+}
+
+fn printed(lines: &[&str]) -> Vec<String> {
+    let (printed, stderr) = repl(lines);
+    assert_eq!(stderr, "");
+    printed
+}
+
+#[test]
+fn print_and_expression_statements() {
+    assert_eq!(
+        printed(&["print 1 + 2;", "1 + 2;", "print \"hi\";"]),
+        ["3", "hi"]
+    );
+}
+
+#[test]
+fn block_comments_need_their_own_close() {
+    assert_eq!(printed(&["print 1; /*/ print 3; */ print 4;"]), ["1", "4"]);
+    assert_eq!(
+        printed(&["print 1; // print 2;", "/* a */ print 3;"]),
+        ["1", "3"]
+    );
+}
+
+#[test]
+fn unterminated_block_comment_is_an_error() {
+    let (printed, stderr) = repl(&["print 1; /* print 2;"]);
+    assert!(printed.is_empty());
+    assert!(stderr.contains("Unterminated comment."), "{}", stderr);
+}
+
+#[test]
+fn global_variables() {
+    assert_eq!(
+        printed(&[
+            "var a = 1;",
+            "var b;",
+            "a = a + 1;",
+            "print a;",
+            "print b == b;"
+        ]),
+        ["2", "true"]
+    );
+}
+
+#[test]
+fn undefined_global_is_a_runtime_error() {
+    let (printed, stderr) = repl(&["print missing;", "missing = 1;", "print 2;"]);
+    assert_eq!(printed, ["2"]);
+    assert_eq!(
+        stderr.matches("Undefined variable 'missing'.").count(),
+        2,
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn block_scoped_locals() {
+    assert_eq!(
+        printed(&["var a = \"global\"; { var a = \"outer\"; { var a = \"inner\"; print a; } print a; } print a;"]),
+        ["inner", "outer", "global"]
+    );
+}
+
+#[test]
+fn local_read_in_its_own_initializer_is_an_error() {
+    let (printed, stderr) = repl(&["{ var a = a; }", "{ var b = 1; var b = 2; }"]);
+    assert!(printed.is_empty());
+    assert!(
+        stderr.contains("Can't read local variable in its own initializer."),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Already a variable with this name in this scope."),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn if_else_while_and_for() {
+    assert_eq!(
+        printed(&[
+            "if (1 < 2) print \"then\"; else print \"else\";",
+            "if (false) print \"skipped\";",
+            "var i = 0; while (i < 3) { print i; i = i + 1; }",
+            "for (var j = 0; j < 2; j = j + 1) print j;",
+            "var k = 0; for (; k < 1;) k = k + 1; print k;",
+        ]),
+        ["then", "0", "1", "2", "0", "1", "1"]
+    );
+}
+
+#[test]
+fn and_or_short_circuit() {
+    assert_eq!(
+        printed(&[
+            "var x = 0;",
+            "print false and (x = 1);",
+            "print true or (x = 2);",
+            "print x;",
+            "print nil or \"default\";",
+            "print 1 and 2;",
+        ]),
+        ["false", "true", "0", "default", "2"]
+    );
+}
+
+#[test]
+fn ternary_only_evaluates_the_chosen_branch() {
+    assert_eq!(
+        printed(&[
+            "var x = 0;",
+            "print true ? \"yes\" : (x = 1);",
+            "print false ? (x = 2) : \"no\";",
+            "print x;",
+            "print false ? 1 : true ? 2 : 3;",
+            "var y = nil ? 1 : 2; print y;",
+        ]),
+        ["yes", "no", "0", "2", "2"]
+    );
+}
+
+#[test]
+fn compound_assignment_to_variables() {
+    assert_eq!(
+        printed(&[
+            "var a = 10; a += 5; a -= 3; a *= 2; a /= 4; print a;",
+            "var s = \"a\"; s += \"b\"; print s;",
+            "{ var l = 1; l += 1; print l; }",
+            "var b = 1; print b += 2;",
+        ]),
+        ["6", "ab", "2", "3"]
+    );
+}
+
+#[test]
+fn functions_calls_and_returns() {
+    assert_eq!(
+        printed(&[
+            "fun add(a, b) { return a + b; }",
+            "print add(1, 2);",
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }",
+            "print fib(10);",
+            "fun noReturn() {} print noReturn() == nil;",
+            "print add;",
+        ]),
+        ["3", "55", "true", "<fn add>"]
+    );
+}
+
+#[test]
+fn call_errors() {
+    let (printed, stderr) = repl(&[
+        "fun f(a) {}",
+        "f();",
+        "\"str\"();",
+        "fun inf() { inf(); } inf();",
+        "print 1;",
+    ]);
+    assert_eq!(printed, ["1"]);
+    assert!(
+        stderr.contains("Expected 1 arguments but got 0."),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Can only call functions and classes."),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("Stack overflow."), "{}", stderr);
+}
+
+#[test]
+fn deep_frames_overflow_without_panicking() {
+    // Each frame holds more than its share of the stack: 250 locals plus
+    // operands waiting on the recursive call.
+    let locals: String = (0..250).map(|i| format!("var l{}; ", i)).collect();
+    let operands = "1 + (".repeat(10);
+    let source = format!(
+        "fun f(n) {{ {} if (n == 0) return 0; return {}f(n - 1){}; }} f(100);",
+        locals,
+        operands,
+        ")".repeat(10)
+    );
+    let (_, stderr) = repl(&[&source, "print 1;"]);
+    assert!(stderr.contains("Stack overflow."), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn closures_capture_variables() {
+    assert_eq!(
+        printed(&[
+            "fun makeCounter() { var i = 0; fun count() { i = i + 1; return i; } return count; }",
+            "var c1 = makeCounter(); var c2 = makeCounter();",
+            "c1(); print c1(); print c2();",
+            "{ var a = 1; fun get() { return a; } fun set(v) { a = v; } set(5); print get(); }",
+            "var fs; { var x = \"closed\"; fun f() { return x; } fs = f; } print fs();",
+            "fun outer() { var y = \"deep\"; fun mid() { fun inner() { return y; } return inner; } return mid()(); } print outer();",
+        ]),
+        ["2", "1", "5", "closed", "deep"]
+    );
+}
+
+#[test]
+fn runtime_error_closes_escaped_upvalues() {
+    let (printed, stderr) = repl(&[
+        "var f; fun g() { var x = \"cap\" + \"tured\"; fun h() { return x; } f = h; nil + 1; } g();",
+        "var junk = \"a\" + \"b\";",
+        "print f();",
+    ]);
+    assert_eq!(printed, ["captured"]);
+    assert!(stderr.contains("Operands must be"), "{}", stderr);
+}
+
+#[test]
+fn classes_fields_methods_and_this() {
+    assert_eq!(
+        printed(&[
+            "class Point { init(x, y) { this.x = x; this.y = y; } sum() { return this.x + this.y; } }",
+            "var p = Point(1, 2);",
+            "print p.sum();",
+            "p.x += 10; p.y *= 3; print p.sum();",
+            "var m = p.sum; p.x = 0; print m();",
+            "p.z = \"field\"; print p.z;",
+            "print Point; print p;",
+            "class Empty {} print Empty();",
+        ]),
+        ["3", "17", "6", "field", "Point", "Point instance", "Empty instance"]
+    );
+}
+
+#[test]
+fn class_errors() {
+    let (printed, stderr) = repl(&[
+        "class A {}",
+        "print A().missing;",
+        "A(1);",
+        "var n = 1; n.field = 2;",
+        "print this;",
+        "class B { init() { return 1; } }",
+        "print 1;",
+    ]);
+    assert_eq!(printed, ["1"]);
+    assert!(
+        stderr.contains("Undefined property 'missing'."),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Expected 0 arguments but got 1."),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("Only instances have fields."), "{}", stderr);
+    assert!(
+        stderr.contains("Can't use 'this' outside of a class."),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Can't return a value from an initializer."),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn inheritance_and_super_calls() {
+    assert_eq!(
+        printed(&[
+            "class A { init(n) { this.n = n; } name() { return \"A\"; } describe() { return this.name() + \"!\"; } }",
+            "class B < A { init(n) { super.init(n * 2); } name() { return \"B\" + super.name(); } }",
+            "var b = B(2);",
+            "print b.n; print b.describe();",
+            "class C < B { name() { var s = super.name; return s(); } } print C(1).name();",
+        ]),
+        ["4", "BA!", "BA"]
+    );
+}
+
+#[test]
+fn inheritance_errors() {
+    let (printed, stderr) = repl(&[
+        "var NotClass = 1; class A < NotClass {}",
+        "class Self < Self {}",
+        "print super.x;",
+        "class D { f() { return super.f(); } }",
+        "print 1;",
+    ]);
+    assert_eq!(printed, ["1"]);
+    assert!(stderr.contains("Superclass must be a class."), "{}", stderr);
+    assert!(
+        stderr.contains("A class can't inherit from itself."),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Can't use 'super' outside of a class."),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Can't use 'super' in a class with no superclass."),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn live_objects_survive_collections() {
+    // Enough garbage to trigger several collections while the counter, its
+    // upvalue and the instance are only reachable through globals.
+    assert_eq!(
+        printed(&[
+            "fun makeCounter() { var i = 0; fun count() { i = i + 1; return i; } return count; }",
+            "class Box { init(v) { this.v = v; } }",
+            "var counter = makeCounter(); var box = Box(\"kept\");",
+            "for (var i = 0; i < 50000; i = i + 1) { var s = \"garbage \" + \"string\"; Box(s); counter(); }",
+            "print counter(); print box.v;",
+        ]),
+        ["50001", "kept"]
+    );
+}
+
+#[test]
+fn equal_strings_are_one_value() {
+    assert_eq!(
+        printed(&[
+            "var a = \"ab\"; var b = \"a\" + \"b\";",
+            "print a == b; print a != \"abc\";",
+            "for (var i = 0; i < 30000; i = i + 1) { var s = \"x\" + \"y\"; }",
+            "print \"x\" + \"y\" == \"xy\";",
+        ]),
+        ["true", "true", "true"]
+    );
+}
+
+#[test]
+fn list_literals_indexing_and_methods() {
+    assert_eq!(
+        printed(&[
+            "var l = [1, \"two\", nil, [3]];",
+            "print l; print []; print l[1]; print l[-1][0];",
+            "l[0] = 10; l[-2] = true; print l;",
+            "var m = [1, 2]; m.push(3); m.insert(0, 0); m.insert(-1, 2.5); print m;",
+            "print m.pop(); print m.remove(-1); print m.remove(0); print m; print m.len();",
+        ]),
+        [
+            "[1, two, nil, [3]]",
+            "[]",
+            "two",
+            "3",
+            "[10, two, true, [3]]",
+            "[0, 1, 2, 2.5, 3]",
+            "3",
+            "2.5",
+            "0",
+            "[1, 2]",
+            "2",
+        ]
+    );
+}
+
+#[test]
+fn compound_index_assignment_evaluates_operands_once() {
+    assert_eq!(
+        printed(&[
+            "var calls = 0; fun f() { calls = calls + 1; return 0; }",
+            "var l = [1, 2]; fun g() { calls = calls + 10; return l; }",
+            "g()[f()] += 5; l[-1] *= 3;",
+            "print l; print calls;",
+            "print l[f()] -= 1;",
+        ]),
+        ["[6, 6]", "11", "5"]
+    );
+}
+
+#[test]
+fn lists_that_contain_themselves_print() {
+    assert_eq!(
+        printed(&[
+            "var a = [1]; a.push(a); print a;",
+            "var b = [a, a]; print b;",
+            "var c = []; var d = [c]; c.push(d); print c;",
+        ]),
+        ["[1, [...]]", "[[1, [...]], [1, [...]]]", "[[[...]]]",]
+    );
+}
+
+#[test]
+fn list_errors() {
+    let (printed, stderr) = repl(&[
+        "var l = [1, 2];",
+        "print l[2];",
+        "print l[-3];",
+        "print l[0.5];",
+        "print l[\"0\"];",
+        "print 1[0];",
+        "[].pop();",
+        "l.push();",
+        "l.missing();",
+        "print l;",
+    ]);
+    assert_eq!(printed, ["[1, 2]"]);
+    for error in [
+        "List index out of range.",
+        "List index must be an integer.",
+        "List index must be a number.",
+        "Only lists and maps can be indexed.",
+        "Can't pop from an empty list.",
+        "Expected 1 arguments but got 0.",
+        "Undefined property 'missing'.",
+    ]
+    .iter()
+    {
+        assert!(stderr.contains(error), "{}", stderr);
+    }
+}
+
+#[test]
+fn map_literals_indexing_and_methods() {
+    assert_eq!(
+        printed(&[
+            "var m = {\"a\": 1, 2: \"two\", true: nil, nil: false};",
+            "print m[\"a\"]; print m[2]; print m[true]; print m[nil]; print m.len();",
+            "m[\"a\"] += 10; m[\"new\"] = [1]; print m[\"a\"]; print m[\"new\"];",
+            "print m.has(2); print m.has(3); print m.remove(2); print m.remove(3); print m.has(2);",
+            "var e = {}; print e; print e.len(); e[1] = 2; print e; print e.keys(); print e.values();",
+            "var k = {\"x\": 1, \"y\": 2}.keys(); print k.len();",
+            "print {\"a\" + \"b\": 1}[\"ab\"]; print {1: 2}[1.0];",
+        ]),
+        [
+            "1", "two", "nil", "false", "4", "11", "[1]", "true", "false", "two", "nil", "false", "{}", "0",
+            "{1: 2}", "[1]", "[2]", "2", "1", "2",
+        ]
+    );
+}
+
+#[test]
+fn maps_that_contain_themselves_print() {
+    assert_eq!(
+        printed(&[
+            "var m = {}; m[\"self\"] = m; print m;",
+            "var l = []; var n = {\"list\": l}; l.push(n); print l; print n;",
+        ]),
+        ["{self: {...}}", "[{list: [...]}]", "{list: [{...}]}"]
+    );
+}
+
+#[test]
+fn map_errors() {
+    let (printed, stderr) = repl(&[
+        "var m = {\"a\": 1};",
+        "print m[\"b\"];",
+        "m[[]] = 1;",
+        "print {0/0: 1};",
+        "m.has();",
+        "m.missing();",
+        "print m;",
+    ]);
+    assert_eq!(printed, ["{a: 1}"]);
+    for error in [
+        "Key not found in map.",
+        "Map keys must be numbers, strings, booleans or nil.",
+        "Map key can't be NaN.",
+        "Expected 1 arguments but got 0.",
+        "Undefined property 'missing'.",
+    ]
+    .iter()
+    {
+        assert!(stderr.contains(error), "{}", stderr);
+    }
+}
+
+#[test]
+fn clock_is_a_native_function() {
+    assert_eq!(
+        printed(&[
+            "print clock;",
+            "var start = clock(); print start > 0;",
+            "for (var i = 0; i < 1000; i = i + 1) {} print clock() >= start;",
+        ]),
+        ["<native fn clock>", "true", "true"]
+    );
+    let (_, stderr) = repl(&["clock(1);"]);
+    assert!(
+        stderr.contains("Expected 0 arguments but got 1."),
+        "{}",
+        stderr
+    );
+}